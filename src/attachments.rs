@@ -0,0 +1,105 @@
+//! Attachments, stickers and embeds, which IRC can only get as a line of text each.
+
+use poise::serenity_prelude::Message;
+
+use crate::formatting;
+
+/// Sums up everything in a message besides its text, one IRC line per item.
+pub fn summarize(msg: &Message) -> Vec<String> {
+    let attachments = msg
+        .attachments
+        .iter()
+        .map(|a| attachment(&a.filename, a.size, &a.url));
+    let stickers = msg.sticker_items.iter().map(|s| sticker(&s.name));
+    // link previews are Discord's rendering of URLs already in the text
+    let embeds = msg
+        .embeds
+        .iter()
+        .filter(|e| e.kind.as_deref() == Some("rich"))
+        .filter_map(|e| {
+            embed(
+                e.title.as_deref(),
+                e.url.as_deref(),
+                e.description.as_deref(),
+            )
+        });
+
+    attachments.chain(stickers).chain(embeds).collect()
+}
+
+pub fn attachment(filename: &str, size: u64, url: &str) -> String {
+    format!("[file: {} ({})] {}", filename, human_size(size), url)
+}
+
+pub fn sticker(name: &str) -> String {
+    format!("[sticker: {}]", name)
+}
+
+/// Title, URL and the first line of the description, or `None` for an embed with none of them.
+pub fn embed(title: Option<&str>, url: Option<&str>, description: Option<&str>) -> Option<String> {
+    let description = description
+        .and_then(|d| d.lines().map(str::trim).find(|l| !l.is_empty()))
+        .map(formatting::discord_to_irc);
+    let parts: Vec<String> = [
+        title.map(str::to_string),
+        url.map(str::to_string),
+        description,
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|p| !p.trim().is_empty())
+    .collect();
+
+    (!parts.is_empty()).then(|| format!("[embed] {}", parts.join(" - ")))
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_readable() {
+        assert_eq!(human_size(532), "532 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[test]
+    fn renders_attachment() {
+        assert_eq!(
+            attachment("cat.png", 2048, "https://cdn.discordapp.com/cat.png"),
+            "[file: cat.png (2.0 KiB)] https://cdn.discordapp.com/cat.png"
+        );
+    }
+
+    #[test]
+    fn embed_keeps_first_line() {
+        assert_eq!(
+            embed(
+                Some("Release"),
+                Some("https://example.com"),
+                Some("\n**v1.0** is out\nmore text")
+            ),
+            Some("[embed] Release - https://example.com - \x02v1.0\x02 is out".to_string())
+        );
+        assert_eq!(
+            embed(Some("Just a title"), None, None),
+            Some("[embed] Just a title".to_string())
+        );
+        assert_eq!(embed(None, None, Some("  ")), None);
+    }
+}
@@ -0,0 +1,199 @@
+//! Identicon avatars for IRC users, served over HTTP so that webhook messages can use them.
+//!
+//! Avatar URLs only carry a hash of the nick, so nicks don't end up anywhere but Discord.
+
+use std::io::Write;
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use axum::extract::Path;
+use axum::http::header;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use tracing::info;
+
+/// Cells on each side of the identicon grid.
+const GRID: usize = 5;
+const CELL_PIXELS: usize = 16;
+const SIZE: usize = GRID * CELL_PIXELS;
+
+const LIGHT_BACKGROUND: u32 = 0xf2f3f5;
+const DARK_BACKGROUND: u32 = 0x202225;
+
+pub fn hash(nick: &str) -> u32 {
+    crc32fast::hash(nick.as_bytes())
+}
+
+/// Where the avatar of a nick is served, below the server's public URL.
+pub fn url(public_url: &str, nick: &str) -> String {
+    format!(
+        "{}/avatar/{:08x}.png",
+        public_url.trim_end_matches('/'),
+        hash(nick)
+    )
+}
+
+/// Serves avatars on `/avatar/<hash>.png` until the listener fails.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let app = Router::new().route("/avatar/:file", get(avatar));
+
+    info!("Serving avatars on {}", addr);
+    axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+async fn avatar(Path(file): Path<String>) -> Response {
+    let hash = file
+        .strip_suffix(".png")
+        .filter(|hex| hex.len() == 8)
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+
+    match hash {
+        Some(hash) => (
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                // the same hash always renders the same picture
+                (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+            ],
+            identicon(hash),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Renders a horizontally symmetric 5x5 identicon as a PNG.
+///
+/// The colour is the one the bridge always used for a nick, the top 24 bits of its hash, and the low bits pick the cells.
+pub fn identicon(hash: u32) -> Vec<u8> {
+    let color = hash >> 8;
+    let (r, g, b) = ((color >> 16) & 0xff, (color >> 8) & 0xff, color & 0xff);
+    let light = r * 299 + g * 587 + b * 114 > 160 * 1000;
+    let background = if light {
+        DARK_BACKGROUND
+    } else {
+        LIGHT_BACKGROUND
+    };
+
+    let filled = |row: usize, col: usize| {
+        // only the left half and the middle column are picked, the right half mirrors them
+        let col = col.min(GRID - 1 - col);
+        (hash >> (row * GRID.div_ceil(2) + col)) & 1 == 1
+    };
+
+    let mut pixels = Vec::with_capacity(SIZE * SIZE * 3);
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let rgb = match filled(y / CELL_PIXELS, x / CELL_PIXELS) {
+                true => color,
+                false => background,
+            };
+            pixels.extend_from_slice(&rgb.to_be_bytes()[1..]);
+        }
+    }
+
+    png(SIZE, SIZE, &pixels)
+}
+
+/// Encodes 8-bit RGB pixels as a PNG.
+fn png(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(rgb.len() + height);
+    for row in rgb.chunks(width * 3) {
+        // every scanline starts with its filter type, 0 for none
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    // writing to a Vec can't fail
+    encoder.write_all(&raw).unwrap();
+    let data = encoder.finish().unwrap();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth 8, colour type RGB, default compression, filtering and no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, body) in [
+        (b"IHDR", header.as_slice()),
+        (b"IDAT", &data),
+        (b"IEND", &[]),
+    ] {
+        out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        let start = out.len();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        let crc = crc32fast::hash(&out[start..]);
+        out.extend_from_slice(&crc.to_be_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    /// Takes the pixels back out of a PNG written by `png`.
+    fn decode(png: &[u8]) -> Vec<u8> {
+        let idat = png.windows(4).position(|w| w == b"IDAT").unwrap();
+        let len = u32::from_be_bytes(png[idat - 4..idat].try_into().unwrap()) as usize;
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&png[idat + 4..idat + 4 + len])
+            .read_to_end(&mut raw)
+            .unwrap();
+        raw.chunks(SIZE * 3 + 1)
+            .flat_map(|row| row[1..].to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn url_hides_nick() {
+        let url = url("https://bridge.example.com/", "alice");
+        assert_eq!(
+            url,
+            format!(
+                "https://bridge.example.com/avatar/{:08x}.png",
+                hash("alice")
+            )
+        );
+        assert!(!url.contains("alice"));
+    }
+
+    #[test]
+    fn writes_png_header() {
+        let png = identicon(hash("alice"));
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 80, 0, 0, 0, 80]);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+    }
+
+    #[test]
+    fn identicon_is_symmetric() {
+        let pixels = decode(&identicon(hash("bob")));
+        assert_eq!(pixels.len(), SIZE * SIZE * 3);
+        for row in pixels.chunks(SIZE * 3) {
+            let pixel = |x: usize| &row[x * 3..x * 3 + 3];
+            for x in 0..SIZE {
+                assert_eq!(pixel(x), pixel(SIZE - 1 - x));
+            }
+        }
+    }
+
+    #[test]
+    fn same_nick_same_picture() {
+        assert_eq!(identicon(hash("carol")), identicon(hash("carol")));
+        assert_ne!(identicon(hash("carol")), identicon(hash("dave")));
+    }
+}
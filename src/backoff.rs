@@ -0,0 +1,75 @@
+//! Jittered exponential backoff for reconnecting.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::time::Duration;
+
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Returns how long to wait before the next attempt.
+    ///
+    /// The delay doubles every time up to `max`, and a random half of it is shaved off
+    /// so that several bridges restarting together don't reconnect in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        delay / 2 + (delay / 2).mul_f64(random_fraction())
+    }
+
+    /// Starts over from the shortest delay, once a connection succeeded.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// A random number in `[0, 1)`, std's hasher seeds are random enough for jitter.
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        let bounds = [1, 2, 4, 8, 10, 10];
+
+        for max in bounds {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(max) / 2);
+            assert!(delay <= Duration::from_secs(max));
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,88 @@
+//! CTCP, the `\x01`-delimited messages IRC uses for `/me` and client queries.
+
+use anyhow::Result;
+use irc::client::Sender;
+use irc::proto::Command;
+use irc::proto::Message;
+use poise::serenity_prelude::Timestamp;
+use tracing::debug;
+
+const DELIMITER: char = '\x01';
+
+/// Splits a CTCP message into its command and parameters, or returns `None` if the text isn't CTCP.
+pub fn parse(text: &str) -> Option<(&str, &str)> {
+    let body = text.strip_prefix(DELIMITER)?;
+    // the closing delimiter is optional, some clients leave it out
+    let body = body.strip_suffix(DELIMITER).unwrap_or(body);
+
+    Some(body.split_once(' ').unwrap_or((body, "")))
+}
+
+/// Returns the text of a `/me`, if the message is a CTCP ACTION.
+pub fn action(text: &str) -> Option<&str> {
+    match parse(text) {
+        Some((command, params)) if command.eq_ignore_ascii_case("ACTION") => Some(params),
+        _ => None,
+    }
+}
+
+/// Wraps text as a CTCP ACTION.
+pub fn make_action(text: &str) -> String {
+    format!("{}ACTION {}{}", DELIMITER, text, DELIMITER)
+}
+
+/// Bytes the ACTION wrapper adds around the text.
+pub const ACTION_OVERHEAD: usize = "\x01ACTION \x01".len();
+
+/// Answers CTCP queries sent to the bridge or to a channel it's in.
+///
+/// Replies always go to the user who asked, as a NOTICE so that they can't trigger another reply.
+pub fn reply(sender: &Sender, message: &Message) -> Result<()> {
+    let Command::PRIVMSG(_, text) = &message.command else {
+        return Ok(());
+    };
+    let (Some((command, params)), Some(nick)) = (parse(text), message.source_nickname()) else {
+        return Ok(());
+    };
+
+    let response = match command.to_ascii_uppercase().as_str() {
+        "VERSION" => format!(
+            "VERSION {} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ),
+        "PING" => format!("PING {}", params),
+        "TIME" => format!("TIME {}", Timestamp::now().to_rfc2822()),
+        "CLIENTINFO" => "CLIENTINFO ACTION CLIENTINFO PING TIME VERSION".to_string(),
+        _ => return Ok(()),
+    };
+
+    debug!("answering CTCP {} from {}", command, nick);
+    sender.send_notice(nick, format!("{}{}{}", DELIMITER, response, DELIMITER))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_action() {
+        assert_eq!(action("\x01ACTION waves\x01"), Some("waves"));
+        assert_eq!(action("\x01ACTION waves"), Some("waves"));
+        assert_eq!(action("waves"), None);
+        assert_eq!(action("\x01VERSION\x01"), None);
+    }
+
+    #[test]
+    fn parses_query_without_params() {
+        assert_eq!(parse("\x01VERSION\x01"), Some(("VERSION", "")));
+    }
+
+    #[test]
+    fn action_round_trip() {
+        let text = make_action("dances");
+        assert_eq!(text.len(), "dances".len() + ACTION_OVERHEAD);
+        assert_eq!(action(&text), Some("dances"));
+    }
+}
@@ -0,0 +1,106 @@
+//! Discord edits, described on IRC as corrections.
+
+/// Substitutions longer than this, counting both sides, are sent as the whole new text instead.
+const MAX_SUBSTITUTION_CHARS: usize = 40;
+
+/// Describes an edit as `s/old/new/` when a few words changed, or as `* new text` otherwise.
+pub fn correction(old: &str, new: &str) -> String {
+    substitution(old, new).unwrap_or_else(|| format!("* {}", new))
+}
+
+fn substitution(old: &str, new: &str) -> Option<String> {
+    if old.contains('\n') || new.contains('\n') {
+        return None;
+    }
+
+    let prefix = word_start(old, common_prefix(old, new));
+    let max_suffix = old.len().min(new.len()) - prefix;
+    let suffix = old.len() - word_end(old, old.len() - common_suffix(old, new, max_suffix));
+
+    let old_part = &old[prefix..old.len() - suffix];
+    let new_part = &new[prefix..new.len() - suffix];
+    let small = old_part.chars().count() + new_part.chars().count() <= MAX_SUBSTITUTION_CHARS;
+    // `s/a/b/` only says which `a` if there is just one
+    let unambiguous = !old_part.is_empty() && old.matches(old_part).count() == 1;
+
+    (small && unambiguous && !old_part.contains('/') && !new_part.contains('/'))
+        .then(|| format!("s/{}/{}/", old_part, new_part))
+}
+
+fn common_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((i, _), _)| i)
+}
+
+/// Bytes at the end of both strings that are the same, at most `max`.
+fn common_suffix(a: &str, b: &str, max: usize) -> usize {
+    a.chars()
+        .rev()
+        .zip(b.chars().rev())
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x.len_utf8())
+        .scan(0, |len, c| {
+            *len += c;
+            Some(*len)
+        })
+        .take_while(|len| *len <= max)
+        .last()
+        .unwrap_or(0)
+}
+
+/// Moves a position back to the start of the word it is in.
+fn word_start(text: &str, i: usize) -> usize {
+    if i == text.len() || text[..i].ends_with(' ') || text[i..].starts_with(' ') {
+        return i;
+    }
+    text[..i].rfind(' ').map_or(0, |space| space + 1)
+}
+
+/// Moves a position forward to the end of the word it is in.
+fn word_end(text: &str, i: usize) -> usize {
+    if i == 0 || text[..i].ends_with(' ') || text[i..].starts_with(' ') {
+        return i;
+    }
+    text[i..].find(' ').map_or(text.len(), |space| i + space)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_change_is_a_substitution() {
+        assert_eq!(
+            correction("I like teh cake", "I like the cake"),
+            "s/teh/the/"
+        );
+        assert_eq!(correction("teh cake", "the cake"), "s/teh/the/");
+        assert_eq!(
+            correction("see you tomorow", "see you tomorrow"),
+            "s/tomorow/tomorrow/"
+        );
+    }
+
+    #[test]
+    fn substitution_covers_whole_words() {
+        assert_eq!(
+            correction("meet at 5pm today", "meet at 6pm today"),
+            "s/5pm/6pm/"
+        );
+        assert_eq!(correction("héllo wörld", "héllo world"), "s/wörld/world/");
+    }
+
+    #[test]
+    fn big_or_ambiguous_changes_are_sent_whole() {
+        assert_eq!(
+            correction("hello world", "goodbye everyone, see you all next week"),
+            "* goodbye everyone, see you all next week"
+        );
+        assert_eq!(correction("a b", "a x b"), "* a x b");
+        assert_eq!(correction("no no", "no yes"), "* no yes");
+        assert_eq!(correction("a/b c", "a/c c"), "* a/c c");
+        assert_eq!(correction("one\ntwo", "one\nthree"), "* one\nthree");
+    }
+}
@@ -0,0 +1,944 @@
+//! Conversion between IRC formatting control codes and Discord markdown, in both directions.
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const RESET: char = '\x0F';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1D';
+const STRIKETHROUGH: char = '\x1E';
+const UNDERLINE: char = '\x1F';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    Monospace,
+}
+
+impl Style {
+    fn markdown(self) -> &'static str {
+        match self {
+            Style::Bold => "**",
+            Style::Italic => "*",
+            Style::Underline => "__",
+            Style::Strikethrough => "~~",
+            Style::Monospace => "`",
+        }
+    }
+
+    fn irc(self) -> char {
+        match self {
+            Style::Bold => BOLD,
+            Style::Italic => ITALIC,
+            Style::Underline => UNDERLINE,
+            Style::Strikethrough => STRIKETHROUGH,
+            Style::Monospace => MONOSPACE,
+        }
+    }
+}
+
+/// Black on black, so the text only shows up when selected.
+const SPOILER_COLOR: &str = "\x0301,01";
+
+/// A run of text sharing the same set of styles, in the order they were turned on.
+struct Segment {
+    styles: Vec<Style>,
+    text: String,
+}
+
+/// Converts IRC formatted text into Discord markdown.
+///
+/// Markdown characters already in the text are escaped, and colours are dropped since Discord can't show them.
+pub fn irc_to_discord(text: &str) -> String {
+    render_markdown(&parse_irc(text))
+}
+
+/// Removes every IRC formatting code from the text, for places where Discord doesn't render markdown.
+pub fn strip_irc_formatting(text: &str) -> String {
+    parse_irc(text).into_iter().map(|s| s.text).collect()
+}
+
+/// Shortens text to a single line of at most `max_chars` characters, marking the cut with an ellipsis.
+pub fn excerpt(text: &str, max_chars: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", line[..end].trim_end()),
+        None => line,
+    }
+}
+
+fn parse_irc(text: &str) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut styles: Vec<Style> = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    let mut push = |styles: &Vec<Style>, c: char| match segments.last_mut() {
+        Some(last) if &last.styles == styles => last.text.push(c),
+        _ => segments.push(Segment {
+            styles: styles.clone(),
+            text: c.to_string(),
+        }),
+    };
+
+    while let Some(c) = chars.next() {
+        let toggled = match c {
+            BOLD => Style::Bold,
+            ITALIC => Style::Italic,
+            UNDERLINE => Style::Underline,
+            STRIKETHROUGH => Style::Strikethrough,
+            MONOSPACE => Style::Monospace,
+            COLOR => {
+                if skip_digits(&mut chars) > 0 {
+                    skip_background(&mut chars, skip_digits);
+                }
+                continue;
+            }
+            HEX_COLOR => {
+                if skip_hex(&mut chars) > 0 {
+                    skip_background(&mut chars, skip_hex);
+                }
+                continue;
+            }
+            RESET => {
+                styles.clear();
+                continue;
+            }
+            REVERSE => continue,
+            c => {
+                push(&styles, c);
+                continue;
+            }
+        };
+
+        if let Some(i) = styles.iter().position(|s| *s == toggled) {
+            styles.remove(i);
+        } else {
+            styles.push(toggled);
+        }
+    }
+
+    segments
+}
+
+type Skipper = fn(&mut std::iter::Peekable<std::str::Chars>) -> usize;
+
+/// Skips the `,background` part of a colour code, but only if a background actually follows the comma.
+fn skip_background(chars: &mut std::iter::Peekable<std::str::Chars>, skip: Skipper) {
+    if chars.peek() != Some(&',') {
+        return;
+    }
+    let mut ahead = chars.clone();
+    ahead.next();
+    if skip(&mut ahead) > 0 {
+        *chars = ahead;
+    }
+}
+
+fn skip_digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> usize {
+    let mut n = 0;
+    while n < 2 && chars.next_if(|c| c.is_ascii_digit()).is_some() {
+        n += 1;
+    }
+    n
+}
+
+fn skip_hex(chars: &mut std::iter::Peekable<std::str::Chars>) -> usize {
+    let mut ahead = chars.clone();
+    for _ in 0..6 {
+        if ahead.next_if(|c| c.is_ascii_hexdigit()).is_none() {
+            return 0;
+        }
+    }
+    *chars = ahead;
+    6
+}
+
+fn render_markdown(segments: &[Segment]) -> String {
+    let mut out = String::new();
+    let mut open: Vec<Style> = Vec::new();
+    // whitespace at the end of a span goes outside its markers, or Discord won't render them
+    let mut pending_ws = String::new();
+    let mut line_start = true;
+
+    for segment in segments {
+        let core = segment.text.trim();
+        if core.is_empty() {
+            pending_ws.push_str(&segment.text);
+            continue;
+        }
+        let lead = &segment.text[..segment.text.len() - segment.text.trim_start().len()];
+        let trail = &segment.text[segment.text.trim_end().len()..];
+
+        // monospace can't contain other markers, so it wraps each segment on its own
+        let wanted: Vec<Style> = segment
+            .styles
+            .iter()
+            .copied()
+            .filter(|s| *s != Style::Monospace)
+            .collect();
+        let common = open.iter().zip(&wanted).take_while(|(a, b)| a == b).count();
+
+        for style in open.drain(common..).rev() {
+            out.push_str(style.markdown());
+        }
+        out.push_str(&pending_ws);
+        out.push_str(lead);
+        pending_ws.clear();
+        if let Some(style) = wanted.get(common) {
+            // "**a*" followed by "*b*" would read as one long marker
+            if out.ends_with(style.markdown().chars().next().unwrap()) {
+                out.push('\u{200B}');
+            }
+        }
+        for style in &wanted[common..] {
+            out.push_str(style.markdown());
+            open.push(*style);
+        }
+
+        if segment.styles.contains(&Style::Monospace) {
+            out.push_str(&code_span(core));
+        } else {
+            escape_markdown(core, line_start, &mut out);
+        }
+        line_start = false;
+        pending_ws.push_str(trail);
+    }
+
+    for style in open.iter().rev() {
+        out.push_str(style.markdown());
+    }
+    out.push_str(&pending_ws);
+    out
+}
+
+/// Wraps text in an inline code span, using a longer delimiter if it contains backticks itself.
+fn code_span(text: &str) -> String {
+    if !text.contains('`') {
+        return format!("`{}`", text);
+    }
+    let pad = if text.starts_with('`') || text.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("``{}{}{}``", pad, text, pad)
+}
+
+fn escape_markdown(text: &str, line_start: bool, out: &mut String) {
+    let mut at_start = line_start;
+    for word in text.split_inclusive(' ') {
+        // escaping inside a link would break it
+        if word.starts_with("http://") || word.starts_with("https://") {
+            out.push_str(word);
+            at_start = false;
+            continue;
+        }
+        for c in word.chars() {
+            let special = matches!(c, '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']')
+                || (at_start && matches!(c, '>' | '#' | '-'));
+            if special {
+                out.push('\\');
+            }
+            out.push(c);
+            at_start = false;
+        }
+    }
+}
+
+/// Escapes markdown in text spliced into a Discord message, like a resolved name, so `discord_to_irc` keeps it as it is.
+pub fn escape(text: &str) -> String {
+    let mut out = String::new();
+    escape_markdown(text, false, &mut out);
+    out
+}
+
+/// Converts Discord markdown into IRC formatted text.
+///
+/// Spoilers become a span with the same foreground and background colour.
+/// Fenced code blocks are put on their own lines, each one in monospace, so the result may contain newlines.
+pub fn discord_to_irc(text: &str) -> String {
+    let mut out = String::new();
+    render_irc(text, &mut out);
+    out
+}
+
+/// Returns the text inside the italics if the whole message is a single italic span.
+pub fn strip_whole_italic(text: &str) -> Option<&str> {
+    match styled_span(text.trim(), "") {
+        Some((Some(Style::Italic), inner, "")) => Some(inner),
+        _ => None,
+    }
+}
+
+fn render_irc(text: &str, out: &mut String) {
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some((inner, after)) = code_block(rest) {
+            let after = after.strip_prefix('\n').unwrap_or(after);
+            push_code_block(inner, after.is_empty(), out);
+            rest = after;
+            continue;
+        }
+        if let Some((inner, after)) = inline_code(rest) {
+            out.push(MONOSPACE);
+            out.push_str(inner);
+            out.push(MONOSPACE);
+            rest = after;
+            continue;
+        }
+        if let Some((style, inner, after)) = styled_span(rest, out) {
+            match style {
+                Some(style) => {
+                    out.push(style.irc());
+                    render_irc(inner, out);
+                    out.push(style.irc());
+                }
+                None => {
+                    out.push_str(SPOILER_COLOR);
+                    render_irc(inner, out);
+                    out.push(COLOR);
+                    // a digit right after the colour reset would be read as a colour code
+                    if after.starts_with(|c: char| c.is_ascii_digit() || c == ',') {
+                        out.push(BOLD);
+                        out.push(BOLD);
+                    }
+                }
+            }
+            rest = after;
+            continue;
+        }
+        if c == '\\' {
+            if let Some(escaped) = rest[1..]
+                .chars()
+                .next()
+                .filter(|c| c.is_ascii_punctuation())
+            {
+                out.push(escaped);
+                rest = &rest[2..];
+                continue;
+            }
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+}
+
+/// Matches a ```fenced``` code block, returning its content without the language tag.
+fn code_block(text: &str) -> Option<(&str, &str)> {
+    let body = text.strip_prefix("```")?;
+    let end = body.find("```")?;
+    let mut inner = &body[..end];
+
+    if let Some((lang, code)) = inner.split_once('\n') {
+        if !lang.is_empty() && !lang.contains(char::is_whitespace) {
+            inner = code;
+        }
+    }
+
+    Some((inner.trim_matches('\n'), &body[end + 3..]))
+}
+
+fn push_code_block(inner: &str, last: bool, out: &mut String) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    let lines: Vec<String> = inner
+        .lines()
+        .map(|l| format!("{}{}{}", MONOSPACE, l, MONOSPACE))
+        .collect();
+    out.push_str(&lines.join("\n"));
+    if !last {
+        out.push('\n');
+    }
+}
+
+/// Matches an `inline code` span delimited by any number of backticks.
+fn inline_code(text: &str) -> Option<(&str, &str)> {
+    let ticks = text.len() - text.trim_start_matches('`').len();
+    if ticks == 0 {
+        return None;
+    }
+    let delimiter = &text[..ticks];
+    let body = &text[ticks..];
+
+    let mut from = 0;
+    while let Some(i) = body[from..].find(delimiter) {
+        let start = from + i;
+        let end = start + ticks;
+        // the closing run has to be exactly as long as the opening one
+        if body[end..].starts_with('`') {
+            from = end + body[end..].len() - body[end..].trim_start_matches('`').len();
+            continue;
+        }
+        let inner = &body[..start];
+        if inner.is_empty() {
+            return None;
+        }
+        let inner = match inner.strip_prefix(' ').and_then(|i| i.strip_suffix(' ')) {
+            Some(trimmed) if !trimmed.is_empty() => trimmed,
+            _ => inner,
+        };
+        return Some((inner, &body[end..]));
+    }
+    None
+}
+
+/// Matches a bold, italic, underline, strikethrough or spoiler span.
+///
+/// Spoilers are returned as a `None` style.
+fn styled_span<'a>(text: &'a str, out: &str) -> Option<(Option<Style>, &'a str, &'a str)> {
+    let rules = [
+        ("**", Some(Style::Bold)),
+        ("__", Some(Style::Underline)),
+        ("*", Some(Style::Italic)),
+        ("_", Some(Style::Italic)),
+        ("~~", Some(Style::Strikethrough)),
+        ("||", None),
+    ];
+
+    for (delimiter, style) in rules {
+        let Some(body) = text.strip_prefix(delimiter) else {
+            continue;
+        };
+        // underscore italics only work at the start of a word
+        if delimiter == "_" && out.ends_with(|c: char| c.is_alphanumeric()) {
+            continue;
+        }
+        if let Some(end) = find_closing(body, delimiter) {
+            return Some((style, &body[..end], &body[end + delimiter.len()..]));
+        }
+    }
+    None
+}
+
+/// Finds where a span opened with `delimiter` ends, skipping escapes, code and doubled delimiters inside it.
+fn find_closing(body: &str, delimiter: &str) -> Option<usize> {
+    let marker = delimiter.chars().next()?;
+    let single = delimiter.len() == 1;
+
+    if body.is_empty() || (single && body.starts_with(char::is_whitespace)) {
+        return None;
+    }
+
+    let mut i = 0;
+    while i < body.len() {
+        let rest = &body[i..];
+        if let Some(escaped) = rest.strip_prefix('\\') {
+            i += 1 + escaped.chars().next().map_or(0, char::len_utf8);
+            continue;
+        }
+        if let Some((_, after)) = inline_code(rest) {
+            i = body.len() - after.len();
+            continue;
+        }
+        if i > 0 && rest.starts_with(delimiter) {
+            let after = &rest[delimiter.len()..];
+            if single {
+                if after.starts_with(marker) {
+                    // a doubled marker belongs to a nested span
+                    i += 2;
+                    continue;
+                }
+                let before = body[..i].chars().last();
+                let closes = match marker {
+                    '_' => !after.starts_with(|c: char| c.is_alphanumeric()),
+                    _ => before.is_some_and(|c| !c.is_whitespace()),
+                };
+                if closes {
+                    return Some(i);
+                }
+            } else if !after.starts_with(marker) {
+                return Some(i);
+            }
+        }
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_unchanged() {
+        assert_eq!(irc_to_discord("hello world"), "hello world");
+    }
+
+    #[test]
+    fn escaped_text_survives_conversion() {
+        for name in [
+            "*star*",
+            "__init__",
+            "~~x~~",
+            "`code`",
+            "||spoiler||",
+            "back\\slash",
+        ] {
+            let text = format!("hi {} and *you*", escape(name));
+            assert_eq!(
+                discord_to_irc(&text),
+                format!("hi {} and {}you{}", name, ITALIC, ITALIC)
+            );
+        }
+    }
+
+    #[test]
+    fn excerpt_cuts_long_text() {
+        assert_eq!(excerpt("short\n  text", 40), "short text");
+        assert_eq!(excerpt("héllo wörld", 6), "héllo…");
+        assert_eq!(excerpt("exactly", 7), "exactly");
+    }
+
+    #[test]
+    fn empty_text() {
+        assert_eq!(irc_to_discord(""), "");
+    }
+
+    #[test]
+    fn unicode_is_unchanged() {
+        assert_eq!(irc_to_discord("héllo 世界 🦀"), "héllo 世界 🦀");
+    }
+
+    #[test]
+    fn bold() {
+        assert_eq!(irc_to_discord("a \x02bold\x02 word"), "a **bold** word");
+    }
+
+    #[test]
+    fn italic() {
+        assert_eq!(irc_to_discord("a \x1Ditalic\x1D word"), "a *italic* word");
+    }
+
+    #[test]
+    fn underline() {
+        assert_eq!(irc_to_discord("a \x1Funder\x1F word"), "a __under__ word");
+    }
+
+    #[test]
+    fn strikethrough() {
+        assert_eq!(irc_to_discord("a \x1Estrike\x1E word"), "a ~~strike~~ word");
+    }
+
+    #[test]
+    fn monospace() {
+        assert_eq!(
+            irc_to_discord("run \x11cargo build\x11 now"),
+            "run `cargo build` now"
+        );
+    }
+
+    #[test]
+    fn unterminated_style_is_closed() {
+        assert_eq!(irc_to_discord("\x02bold to the end"), "**bold to the end**");
+    }
+
+    #[test]
+    fn reset_closes_everything() {
+        assert_eq!(irc_to_discord("\x02\x1Dboth\x0F plain"), "***both*** plain");
+    }
+
+    #[test]
+    fn nested_styles() {
+        assert_eq!(
+            irc_to_discord("\x02bold \x1Dboth\x1D bold\x02"),
+            "**bold *both* bold**"
+        );
+    }
+
+    #[test]
+    fn overlapping_styles_are_reopened() {
+        assert_eq!(irc_to_discord("\x02a\x1Db\x02c\x1D"), "**a*b***\u{200B}*c*");
+    }
+
+    #[test]
+    fn empty_spans_are_dropped() {
+        assert_eq!(irc_to_discord("a\x02\x02b"), "ab");
+        assert_eq!(irc_to_discord("a\x02\x1D\x0Fb"), "ab");
+    }
+
+    #[test]
+    fn whitespace_moves_outside_markers() {
+        assert_eq!(irc_to_discord("\x02 bold \x02x"), " **bold** x");
+        assert_eq!(irc_to_discord("x\x1D  it\x1D"), "x  *it*");
+    }
+
+    #[test]
+    fn whitespace_only_span_loses_style() {
+        assert_eq!(irc_to_discord("a\x02 \x02b"), "a b");
+    }
+
+    #[test]
+    fn colour_foreground() {
+        assert_eq!(irc_to_discord("\x034red\x03 text"), "red text");
+    }
+
+    #[test]
+    fn colour_two_digits() {
+        assert_eq!(irc_to_discord("\x0312blue"), "blue");
+    }
+
+    #[test]
+    fn colour_with_background() {
+        assert_eq!(irc_to_discord("\x0304,12text"), "text");
+        assert_eq!(irc_to_discord("\x034,2text"), "text");
+    }
+
+    #[test]
+    fn colour_keeps_following_digits() {
+        assert_eq!(irc_to_discord("\x03041234"), "1234");
+        assert_eq!(irc_to_discord("\x0304,12345"), "345");
+    }
+
+    #[test]
+    fn colour_comma_without_background_is_text() {
+        assert_eq!(irc_to_discord("\x034,hello"), ",hello");
+    }
+
+    #[test]
+    fn colour_without_digits_is_reset() {
+        assert_eq!(irc_to_discord("\x03,12x"), ",12x");
+        assert_eq!(irc_to_discord("a\x03b"), "ab");
+    }
+
+    #[test]
+    fn hex_colour() {
+        assert_eq!(irc_to_discord("\x04FF0000red"), "red");
+        assert_eq!(irc_to_discord("\x04FF0000,00FF00both"), "both");
+    }
+
+    #[test]
+    fn hex_colour_short_is_text() {
+        assert_eq!(irc_to_discord("\x04FF00 x"), "FF00 x");
+    }
+
+    #[test]
+    fn reverse_is_dropped() {
+        assert_eq!(irc_to_discord("\x16reversed\x16"), "reversed");
+    }
+
+    #[test]
+    fn colour_inside_bold() {
+        assert_eq!(irc_to_discord("\x02\x034bold red\x03\x02"), "**bold red**");
+    }
+
+    #[test]
+    fn escapes_asterisks() {
+        assert_eq!(irc_to_discord("2*3*4"), "2\\*3\\*4");
+    }
+
+    #[test]
+    fn escapes_underscores() {
+        assert_eq!(irc_to_discord("snake_case_name"), "snake\\_case\\_name");
+    }
+
+    #[test]
+    fn escapes_tildes_and_pipes() {
+        assert_eq!(
+            irc_to_discord("~~no~~ ||spoiler||"),
+            "\\~\\~no\\~\\~ \\|\\|spoiler\\|\\|"
+        );
+    }
+
+    #[test]
+    fn escapes_backticks() {
+        assert_eq!(irc_to_discord("`code`"), "\\`code\\`");
+    }
+
+    #[test]
+    fn escapes_backslash() {
+        assert_eq!(irc_to_discord("C:\\path"), "C:\\\\path");
+    }
+
+    #[test]
+    fn escapes_masked_links() {
+        assert_eq!(
+            irc_to_discord("[click](https://example.com)"),
+            "\\[click\\](https://example.com)"
+        );
+    }
+
+    #[test]
+    fn escapes_quote_at_line_start() {
+        assert_eq!(irc_to_discord("> quoted"), "\\> quoted");
+        assert_eq!(irc_to_discord("a > b"), "a > b");
+    }
+
+    #[test]
+    fn escapes_header_at_line_start() {
+        assert_eq!(irc_to_discord("# big"), "\\# big");
+        assert_eq!(irc_to_discord("issue #12"), "issue #12");
+    }
+
+    #[test]
+    fn escapes_list_at_line_start() {
+        assert_eq!(irc_to_discord("- item"), "\\- item");
+        assert_eq!(irc_to_discord("e-mail"), "e-mail");
+    }
+
+    #[test]
+    fn escapes_line_start_after_leading_whitespace() {
+        assert_eq!(irc_to_discord("\x02> x\x02"), "**\\> x**");
+    }
+
+    #[test]
+    fn escapes_inside_bold() {
+        assert_eq!(irc_to_discord("\x02a*b\x02"), "**a\\*b**");
+    }
+
+    #[test]
+    fn urls_are_not_escaped() {
+        assert_eq!(
+            irc_to_discord("see https://example.com/a_b_c now"),
+            "see https://example.com/a_b_c now"
+        );
+        assert_eq!(irc_to_discord("http://x.y/*z*"), "http://x.y/*z*");
+    }
+
+    #[test]
+    fn monospace_is_not_escaped() {
+        assert_eq!(irc_to_discord("\x11a_b*c\x11"), "`a_b*c`");
+    }
+
+    #[test]
+    fn monospace_containing_backtick() {
+        assert_eq!(irc_to_discord("\x11a`b\x11"), "``a`b``");
+        assert_eq!(irc_to_discord("\x11`x\x11"), "`` `x ``");
+    }
+
+    #[test]
+    fn monospace_inside_bold() {
+        assert_eq!(irc_to_discord("\x02see \x11code\x11\x02"), "**see `code`**");
+    }
+
+    #[test]
+    fn bold_inside_monospace_is_ignored() {
+        assert_eq!(irc_to_discord("\x11a\x02b\x02c\x11"), "`a`**`b`**`c`");
+    }
+
+    #[test]
+    fn all_styles_at_once() {
+        assert_eq!(
+            irc_to_discord("\x02\x1D\x1F\x1Eall\x0F"),
+            "***__~~all~~__***"
+        );
+    }
+
+    #[test]
+    fn toggling_same_style_twice() {
+        assert_eq!(irc_to_discord("\x02a\x02b\x02c\x02"), "**a**b**c**");
+    }
+
+    #[test]
+    fn strip_removes_all_codes() {
+        assert_eq!(
+            strip_irc_formatting("\x02bold\x02 \x0304,12red\x03 \x1Dit\x0F *raw*"),
+            "bold red it *raw*"
+        );
+    }
+
+    #[test]
+    fn discord_plain_text_is_unchanged() {
+        assert_eq!(discord_to_irc("hello world"), "hello world");
+    }
+
+    #[test]
+    fn discord_bold() {
+        assert_eq!(discord_to_irc("a **bold** word"), "a \x02bold\x02 word");
+    }
+
+    #[test]
+    fn discord_italic() {
+        assert_eq!(discord_to_irc("a *italic* word"), "a \x1Ditalic\x1D word");
+        assert_eq!(discord_to_irc("a _italic_ word"), "a \x1Ditalic\x1D word");
+    }
+
+    #[test]
+    fn discord_underline() {
+        assert_eq!(discord_to_irc("__under__"), "\x1Funder\x1F");
+    }
+
+    #[test]
+    fn discord_strikethrough() {
+        assert_eq!(discord_to_irc("~~gone~~"), "\x1Egone\x1E");
+    }
+
+    #[test]
+    fn discord_bold_italic() {
+        assert_eq!(discord_to_irc("***both***"), "\x02\x1Dboth\x1D\x02");
+    }
+
+    #[test]
+    fn discord_nested() {
+        assert_eq!(
+            discord_to_irc("**bold *both* bold**"),
+            "\x02bold \x1Dboth\x1D bold\x02"
+        );
+        assert_eq!(
+            discord_to_irc("*it **both** it*"),
+            "\x1Dit \x02both\x02 it\x1D"
+        );
+        assert_eq!(
+            discord_to_irc("__*under italic*__"),
+            "\x1F\x1Dunder italic\x1D\x1F"
+        );
+    }
+
+    #[test]
+    fn discord_spoiler() {
+        assert_eq!(discord_to_irc("||secret||"), "\x0301,01secret\x03");
+    }
+
+    #[test]
+    fn discord_spoiler_followed_by_digit() {
+        assert_eq!(discord_to_irc("||x||5"), "\x0301,01x\x03\x02\x025");
+    }
+
+    #[test]
+    fn discord_inline_code() {
+        assert_eq!(
+            discord_to_irc("run `cargo build`"),
+            "run \x11cargo build\x11"
+        );
+    }
+
+    #[test]
+    fn discord_inline_code_is_literal() {
+        assert_eq!(discord_to_irc("`**not bold**`"), "\x11**not bold**\x11");
+    }
+
+    #[test]
+    fn discord_double_backtick_code() {
+        assert_eq!(discord_to_irc("`` a`b ``"), "\x11a`b\x11");
+    }
+
+    #[test]
+    fn discord_code_block() {
+        assert_eq!(
+            discord_to_irc("```\nlet a = 1;\nlet b = 2;\n```"),
+            "\x11let a = 1;\x11\n\x11let b = 2;\x11"
+        );
+    }
+
+    #[test]
+    fn discord_code_block_drops_language() {
+        assert_eq!(
+            discord_to_irc("```rust\nfn main() {}\n```"),
+            "\x11fn main() {}\x11"
+        );
+    }
+
+    #[test]
+    fn discord_code_block_on_one_line() {
+        assert_eq!(discord_to_irc("```a b```"), "\x11a b\x11");
+    }
+
+    #[test]
+    fn discord_code_block_between_text() {
+        assert_eq!(
+            discord_to_irc("look:\n```\nx\n```\nnice"),
+            "look:\n\x11x\x11\nnice"
+        );
+        assert_eq!(discord_to_irc("see ```x``` ok"), "see \n\x11x\x11\n ok");
+    }
+
+    #[test]
+    fn discord_code_block_is_literal() {
+        assert_eq!(discord_to_irc("```\n**a**\n```"), "\x11**a**\x11");
+    }
+
+    #[test]
+    fn discord_escapes() {
+        assert_eq!(discord_to_irc("\\*not italic\\*"), "*not italic*");
+        assert_eq!(discord_to_irc("a\\_b"), "a_b");
+        assert_eq!(discord_to_irc("\\\\"), "\\");
+    }
+
+    #[test]
+    fn discord_backslash_before_letter_is_kept() {
+        assert_eq!(discord_to_irc("C:\\path"), "C:\\path");
+    }
+
+    #[test]
+    fn discord_escaped_marker_inside_span() {
+        assert_eq!(discord_to_irc("**a\\*b**"), "\x02a*b\x02");
+    }
+
+    #[test]
+    fn discord_unmatched_markers_are_literal() {
+        assert_eq!(discord_to_irc("2 * 3 = 6"), "2 * 3 = 6");
+        assert_eq!(discord_to_irc("**open"), "**open");
+        assert_eq!(discord_to_irc("~~"), "~~");
+        assert_eq!(discord_to_irc("`"), "`");
+        assert_eq!(discord_to_irc("````"), "````");
+    }
+
+    #[test]
+    fn discord_italic_needs_text_after_marker() {
+        assert_eq!(discord_to_irc("* not italic*"), "* not italic*");
+        assert_eq!(discord_to_irc("*not italic *"), "*not italic *");
+    }
+
+    #[test]
+    fn discord_underscores_inside_words() {
+        assert_eq!(discord_to_irc("snake_case_name"), "snake_case_name");
+        assert_eq!(discord_to_irc("_a_b_"), "\x1Da_b\x1D");
+    }
+
+    #[test]
+    fn discord_unicode() {
+        assert_eq!(discord_to_irc("**世界** 🦀"), "\x02世界\x02 🦀");
+    }
+
+    #[test]
+    fn whole_italic_message() {
+        assert_eq!(strip_whole_italic("*waves*"), Some("waves"));
+        assert_eq!(
+            strip_whole_italic("_waves at **you**_"),
+            Some("waves at **you**")
+        );
+        assert_eq!(strip_whole_italic("*a* and *b*"), None);
+        assert_eq!(strip_whole_italic("**bold**"), None);
+        assert_eq!(strip_whole_italic("plain"), None);
+    }
+
+    #[test]
+    fn round_trip_from_irc() {
+        let cases = [
+            "plain text",
+            "\x02bold\x02",
+            "\x1Ditalic\x1D and \x1Funder\x1F",
+            "\x1Estrike\x1E",
+            "\x11code\x11",
+            "\x02bold \x1Dboth\x1D bold\x02",
+            "a_b*c~d|e`f\\g",
+            "> not a quote",
+            "[not](a link)",
+            "https://example.com/a_b",
+        ];
+        for case in cases {
+            assert_eq!(discord_to_irc(&irc_to_discord(case)), case);
+        }
+    }
+
+    #[test]
+    fn round_trip_from_discord() {
+        let cases = [
+            "plain text",
+            "**bold**",
+            "*italic* and __under__",
+            "~~strike~~",
+            "`code`",
+            "**bold *both* bold**",
+        ];
+        for case in cases {
+            assert_eq!(irc_to_discord(&discord_to_irc(case)), case);
+        }
+    }
+}
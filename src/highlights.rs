@@ -0,0 +1,139 @@
+//! IRC highlights of Discord users and roles, turned into pings.
+
+use std::ops::Range;
+
+use poise::serenity_prelude::RoleId;
+use poise::serenity_prelude::UserId;
+
+/// Where placeholders for pings start, in a private use area IRC text has no business using.
+const PLACEHOLDER_BASE: u32 = 0xE000;
+const MAX_PLACEHOLDERS: usize = 0x1900;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ping {
+    User(UserId),
+    Role(RoleId),
+}
+
+impl Ping {
+    fn mention(self) -> String {
+        match self {
+            Ping::User(id) => format!("<@{}>", id),
+            Ping::Role(id) => format!("<@&{}>", id),
+        }
+    }
+}
+
+fn is_nick_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "[]\\`_^{|}-".contains(c)
+}
+
+/// Finds the nicks an IRC message addresses: a leading `nick:` or `nick,`, and `@nick` anywhere.
+///
+/// Returns each nick with the bytes to replace, which include the `@` but not the `:` or `,`.
+pub fn find(text: &str) -> Vec<(Range<usize>, &str)> {
+    let mut found = Vec::new();
+
+    let end = text.find(|c| !is_nick_char(c)).unwrap_or(text.len());
+    if end > 0 && text[end..].starts_with([':', ',']) {
+        found.push((0..end, &text[..end]));
+    }
+
+    for (at, _) in text.match_indices('@') {
+        // `user@host` and email addresses aren't highlights
+        if text[..at].ends_with(|c: char| c.is_alphanumeric() || is_nick_char(c)) {
+            continue;
+        }
+        let start = at + 1;
+        let end = text[start..]
+            .find(|c| !is_nick_char(c))
+            .map_or(text.len(), |i| start + i);
+        if end > start {
+            found.push((at..end, &text[start..end]));
+        }
+    }
+
+    found
+}
+
+/// Converts an IRC message for Discord with `convert`, turning the highlights `resolve` knows into pings.
+///
+/// Returns the converted text, and who it pings.
+pub fn ping(
+    text: &str,
+    resolve: impl Fn(&str) -> Option<Ping>,
+    convert: impl Fn(&str) -> String,
+) -> (String, Vec<Ping>) {
+    let is_placeholder = |c: char| {
+        (PLACEHOLDER_BASE..PLACEHOLDER_BASE + MAX_PLACEHOLDERS as u32).contains(&(c as u32))
+    };
+    if text.contains(is_placeholder) {
+        return (convert(text), Vec::new());
+    }
+
+    // pings are swapped for placeholders, so that `convert` doesn't escape them
+    let mut marked = String::with_capacity(text.len());
+    let mut pings = Vec::new();
+    let mut last = 0;
+    for (range, nick) in find(text).into_iter().take(MAX_PLACEHOLDERS) {
+        let Some(id) = resolve(nick) else {
+            continue;
+        };
+        marked.push_str(&text[last..range.start]);
+        marked.extend(char::from_u32(PLACEHOLDER_BASE + pings.len() as u32));
+        pings.push(id);
+        last = range.end;
+    }
+    marked.push_str(&text[last..]);
+
+    let converted = convert(&marked)
+        .chars()
+        .map(|c| match is_placeholder(c) {
+            true => pings[(c as u32 - PLACEHOLDER_BASE) as usize].mention(),
+            false => c.to_string(),
+        })
+        .collect();
+
+    let mut unique = pings.clone();
+    unique.sort();
+    unique.dedup();
+    (converted, unique)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nicks(text: &str) -> Vec<&str> {
+        find(text).into_iter().map(|(_, nick)| nick).collect()
+    }
+
+    #[test]
+    fn finds_leading_nick() {
+        assert_eq!(nicks("alice: are you there?"), vec!["alice"]);
+        assert_eq!(nicks("alice, hi"), vec!["alice"]);
+        assert_eq!(nicks("alice hi"), Vec::<&str>::new());
+        assert_eq!(nicks(": hi"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn finds_at_nicks() {
+        assert_eq!(nicks("ask @bob or @[carol]!"), vec!["bob", "[carol]"]);
+        assert_eq!(nicks("mail me@example.com"), Vec::<&str>::new());
+        assert_eq!(nicks("a lone @ sign"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn pings_known_nicks() {
+        let resolve = |nick: &str| match nick {
+            "al_ice" => Some(Ping::User(UserId(1))),
+            "mods" => Some(Ping::Role(RoleId(2))),
+            _ => None,
+        };
+        let convert = |text: &str| text.replace('_', "\\_");
+
+        let (text, pings) = ping("al_ice: hi @al_ice, @mods and @bob_", resolve, convert);
+        assert_eq!(text, "<@1>: hi <@1>, <@&2> and @bob\\_");
+        assert_eq!(pings, vec![Ping::User(UserId(1)), Ping::Role(RoleId(2))]);
+    }
+}
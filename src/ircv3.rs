@@ -0,0 +1,549 @@
+//! IRCv3 capability negotiation and the extensions the bridge uses.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use irc::client::Sender;
+use irc::proto::message::Tag;
+use irc::proto::BatchSubCommand;
+use irc::proto::CapSubCommand;
+use irc::proto::Command;
+use irc::proto::Message;
+use tracing::debug;
+use tracing::info;
+
+use crate::IrcClient;
+use crate::IrcConfig;
+
+/// Capabilities the bridge requests whenever the server offers them.
+const WANTED: &[&str] = &[
+    "batch",
+    "draft/message-redaction",
+    "draft/multiline",
+    "echo-message",
+    "labeled-response",
+    "message-tags",
+];
+
+static NEXT_BATCH: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    /// everything the server offered in `CAP LS`, with its value if it has one
+    available: HashMap<String, Option<String>>,
+    enabled: HashSet<String>,
+}
+
+/// Limits the server advertised for `draft/multiline` batches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultilineLimits {
+    pub max_bytes: usize,
+    pub max_lines: Option<usize>,
+}
+
+impl Capabilities {
+    /// Starts registration with capability negotiation.
+    ///
+    /// This replaces `Client::identify`, which sends `CAP END` before the server could even list its capabilities.
+    pub fn register(client: &IrcClient, config: &IrcConfig) -> Result<()> {
+        client.send(Command::CAP(
+            None,
+            CapSubCommand::LS,
+            Some("302".to_string()),
+            None,
+        ))?;
+        if !config.password().is_empty() {
+            client.send(Command::PASS(config.password().to_string()))?;
+        }
+        client.send(Command::NICK(config.nickname()?.to_string()))?;
+        client.send(Command::USER(
+            config.username().to_string(),
+            "0".to_string(),
+            config.real_name().to_string(),
+        ))?;
+        Ok(())
+    }
+
+    /// Follows the negotiation started by [`Capabilities::register`], ending it once the server answered our request.
+    pub fn handle(&mut self, message: &Message, sender: &Sender) -> Result<()> {
+        let Command::CAP(_, sub, first, second) = &message.command else {
+            return Ok(());
+        };
+
+        match sub {
+            CapSubCommand::LS => {
+                // "CAP * LS * :caps" means more lines follow, the last one is "CAP * LS :caps"
+                let (more, caps) = match (first, second) {
+                    (Some(star), Some(caps)) if star == "*" => (true, caps),
+                    (Some(caps), _) => (false, caps),
+                    _ => return Ok(()),
+                };
+                for cap in caps.split_whitespace() {
+                    match cap.split_once('=') {
+                        Some((name, value)) => self
+                            .available
+                            .insert(name.to_string(), Some(value.to_string())),
+                        None => self.available.insert(cap.to_string(), None),
+                    };
+                }
+                if more {
+                    return Ok(());
+                }
+
+                let request: Vec<&str> = WANTED
+                    .iter()
+                    .copied()
+                    .filter(|c| self.available.contains_key(*c))
+                    .collect();
+                if request.is_empty() {
+                    sender.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+                } else {
+                    debug!("requesting capabilities: {}", request.join(" "));
+                    sender.send(Command::CAP(
+                        None,
+                        CapSubCommand::REQ,
+                        None,
+                        Some(request.join(" ")),
+                    ))?;
+                }
+            }
+            CapSubCommand::ACK => {
+                if let Some(caps) = second.as_ref().or(first.as_ref()) {
+                    for cap in caps.split_whitespace() {
+                        match cap.strip_prefix('-') {
+                            Some(removed) => self.enabled.remove(removed),
+                            None => self.enabled.insert(cap.to_string()),
+                        };
+                    }
+                    info!("IRC capabilities enabled: {}", caps);
+                }
+                sender.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+            }
+            CapSubCommand::NAK => {
+                sender.send(Command::CAP(None, CapSubCommand::END, None, None))?;
+            }
+            CapSubCommand::DEL => {
+                if let Some(caps) = second.as_ref().or(first.as_ref()) {
+                    for cap in caps.split_whitespace() {
+                        self.enabled.remove(cap);
+                        self.available.remove(cap);
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    pub fn enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    /// Whether a label on our messages comes back with their msgid, through their echo.
+    pub fn labels_echoed(&self) -> bool {
+        self.enabled("echo-message") && self.enabled("labeled-response")
+    }
+
+    /// Returns the multiline limits, if the server lets us send multiline batches.
+    pub fn multiline(&self) -> Option<MultilineLimits> {
+        if !self.enabled("batch") || !self.enabled("draft/multiline") {
+            return None;
+        }
+
+        let value = self.available.get("draft/multiline")?.as_deref()?;
+        let mut max_bytes = None;
+        let mut max_lines = None;
+        for param in value.split(',') {
+            match param.split_once('=') {
+                Some(("max-bytes", n)) => max_bytes = n.parse().ok(),
+                Some(("max-lines", n)) => max_lines = n.parse().ok(),
+                _ => (),
+            }
+        }
+
+        Some(MultilineLimits {
+            max_bytes: max_bytes?,
+            max_lines,
+        })
+    }
+}
+
+/// A line of text to send, possibly in a multiline batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Line {
+    /// template text around the line, like the author's nick, left out where a split line is joined back
+    pub prefix: String,
+    pub text: String,
+    pub suffix: String,
+    /// the rest of the line before it, split off for length, joined back without a newline in batches
+    pub concat: bool,
+}
+
+impl Line {
+    /// The line as sent on its own, with its prefix and suffix.
+    pub fn full(&self) -> String {
+        self.render(true, true)
+    }
+
+    fn render(&self, with_prefix: bool, with_suffix: bool) -> String {
+        let mut text = self.text.as_str();
+        let mut out = String::new();
+        if with_prefix {
+            out.push_str(&self.prefix);
+            // the space the line was split at
+            if self.concat {
+                text = text.strip_prefix(' ').unwrap_or(text);
+            }
+        }
+        out.push_str(text);
+        if with_suffix {
+            out.push_str(&self.suffix);
+        }
+        out
+    }
+
+    fn len(&self) -> usize {
+        self.prefix.len() + self.text.len() + self.suffix.len()
+    }
+}
+
+/// Renders the lines of one batch, joining the pieces of split lines that are in it.
+///
+/// A split line cut off by the start or end of the batch gets its prefix or suffix back,
+/// so every batch and every line sent alone still names its author.
+pub fn batch_texts(batch: &[Line]) -> Vec<String> {
+    batch
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            let joined_before = line.concat && i > 0;
+            let joined_after = batch.get(i + 1).is_some_and(|next| next.concat);
+            line.render(!joined_before, !joined_after)
+        })
+        .collect()
+}
+
+/// Returns the value of a message tag, or `None` if the message doesn't have it or it has no value.
+pub fn tag<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    message
+        .tags
+        .iter()
+        .flatten()
+        .find(|Tag(key, _)| key == name)
+        .and_then(|Tag(_, value)| value.as_deref())
+}
+
+/// Sends lines to a target as `draft/multiline` batches, as many as needed to stay within the server's limits.
+///
+/// `tags` go on the first batch, or the first line if it goes alone.
+/// Lines that continue the one before them are tagged `draft/multiline-concat`, unless they start a batch.
+/// Returns how many batches or lone lines that took.
+pub fn send_multiline(
+    sender: &Sender,
+    target: &str,
+    lines: &[Line],
+    limits: MultilineLimits,
+    tags: Vec<Tag>,
+) -> Result<usize> {
+    let mut tags = Some(tags).filter(|t| !t.is_empty());
+    let batches = group_lines(lines, limits);
+    for &batch in &batches {
+        if let [line] = batch {
+            sender.send(Message {
+                tags: tags.take(),
+                prefix: None,
+                command: Command::PRIVMSG(target.to_string(), line.full()),
+            })?;
+            continue;
+        }
+
+        let reference = format!("ml{}", NEXT_BATCH.fetch_add(1, Ordering::Relaxed));
+        sender.send(Message {
+            tags: tags.take(),
+            prefix: None,
+            command: Command::BATCH(
+                format!("+{}", reference),
+                Some(BatchSubCommand::CUSTOM("draft/multiline".to_string())),
+                Some(vec![target.to_string()]),
+            ),
+        })?;
+        for (i, (line, text)) in batch.iter().zip(batch_texts(batch)).enumerate() {
+            let mut line_tags = vec![Tag("batch".to_string(), Some(reference.clone()))];
+            if line.concat && i > 0 {
+                line_tags.push(Tag("draft/multiline-concat".to_string(), None));
+            }
+            sender.send(Message {
+                tags: Some(line_tags),
+                prefix: None,
+                command: Command::PRIVMSG(target.to_string(), text),
+            })?;
+        }
+        sender.send(Command::BATCH(format!("-{}", reference), None, None))?;
+    }
+
+    Ok(batches.len())
+}
+
+/// Groups consecutive lines so that no group goes over the byte or line limit.
+fn group_lines(lines: &[Line], limits: MultilineLimits) -> Vec<&[Line]> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+
+    for (i, line) in lines.iter().enumerate() {
+        // lines are joined with a newline on the receiving end, unless they are concatenated,
+        // and counted with their prefix and suffix in case they end up starting or ending a batch
+        let added = if i == start || line.concat {
+            line.len()
+        } else {
+            line.len() + 1
+        };
+        let too_many = limits.max_lines.is_some_and(|max| i - start >= max);
+
+        if i > start && (too_many || bytes + added > limits.max_bytes) {
+            groups.push(&lines[start..i]);
+            start = i;
+            bytes = line.len();
+        } else {
+            bytes += added;
+        }
+    }
+    if start < lines.len() {
+        groups.push(&lines[start..]);
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use poise::serenity_prelude::futures::StreamExt;
+    use tokio::io::AsyncBufReadExt;
+    use tokio::io::AsyncWriteExt;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn lines(n: usize) -> Vec<Line> {
+        (0..n)
+            .map(|i| Line {
+                text: format!("line {}", i),
+                ..Line::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn groups_by_max_lines() {
+        let lines = lines(5);
+        let limits = MultilineLimits {
+            max_bytes: 4096,
+            max_lines: Some(2),
+        };
+        let groups: Vec<usize> = group_lines(&lines, limits)
+            .iter()
+            .map(|g| g.len())
+            .collect();
+        assert_eq!(groups, vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn groups_by_max_bytes() {
+        let lines = lines(3);
+        // "line 0\nline 1" is 13 bytes
+        let limits = MultilineLimits {
+            max_bytes: 13,
+            max_lines: None,
+        };
+        let groups: Vec<usize> = group_lines(&lines, limits)
+            .iter()
+            .map(|g| g.len())
+            .collect();
+        assert_eq!(groups, vec![2, 1]);
+    }
+
+    #[test]
+    fn multiline_needs_max_bytes() {
+        let mut caps = Capabilities::default();
+        caps.enabled.insert("batch".to_string());
+        caps.enabled.insert("draft/multiline".to_string());
+        caps.available.insert(
+            "draft/multiline".to_string(),
+            Some("max-lines=4".to_string()),
+        );
+        assert_eq!(caps.multiline(), None);
+    }
+
+    /// Runs a minimal IRC server that offers `caps`, and reports every line the client sends after registering.
+    async fn mock_server(caps: &'static str) -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut reader = BufReader::new(read).lines();
+            let mut registered = false;
+
+            while let Ok(Some(line)) = reader.next_line().await {
+                let reply = if line.starts_with("CAP LS") {
+                    format!(":mock CAP * LS :{}\r\n", caps)
+                } else if let Some(req) = line.strip_prefix("CAP REQ ") {
+                    format!(":mock CAP bridge ACK :{}\r\n", req.trim_start_matches(':'))
+                } else if line == "CAP END" {
+                    registered = true;
+                    ":mock 001 bridge :Welcome\r\n".to_string()
+                } else {
+                    if registered {
+                        tx.send(line).unwrap();
+                    }
+                    continue;
+                };
+                write.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+
+        (port, rx)
+    }
+
+    /// Connects to the mock server and negotiates, then keeps the stream polled so that sent messages get flushed.
+    async fn connect(port: u16) -> (IrcClient, Capabilities) {
+        let config = IrcConfig {
+            nickname: Some("bridge".to_string()),
+            server: Some("127.0.0.1".to_string()),
+            port: Some(port),
+            use_tls: Some(false),
+            ..Default::default()
+        };
+        let mut client = IrcClient::from_config(config.clone()).await.unwrap();
+        Capabilities::register(&client, &config).unwrap();
+
+        let mut stream = client.stream().unwrap();
+        let sender = client.sender();
+        let mut caps = Capabilities::default();
+        while let Some(message) = stream.next().await {
+            let message = message.unwrap();
+            caps.handle(&message, &sender).unwrap();
+            if let Command::Response(irc::proto::Response::RPL_WELCOME, _) = message.command {
+                break;
+            }
+        }
+        tokio::spawn(async move { while stream.next().await.is_some() {} });
+
+        (client, caps)
+    }
+
+    async fn received(rx: &mut mpsc::UnboundedReceiver<String>, n: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        for _ in 0..n {
+            lines.push(rx.recv().await.unwrap());
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn sends_batches_within_server_limits() {
+        let (port, mut rx) =
+            mock_server("batch draft/multiline=max-bytes=4096,max-lines=2 sasl").await;
+        let (client, caps) = connect(port).await;
+
+        let limits = caps.multiline().unwrap();
+        assert_eq!(
+            limits,
+            MultilineLimits {
+                max_bytes: 4096,
+                max_lines: Some(2)
+            }
+        );
+        let label = Tag("label".to_string(), Some("1".to_string()));
+        let batches =
+            send_multiline(&client.sender(), "#test", &lines(3), limits, vec![label]).unwrap();
+        assert_eq!(batches, 2);
+
+        let sent = received(&mut rx, 5).await;
+        let reference = sent[0]
+            .strip_prefix("@label=1 BATCH +")
+            .and_then(|s| s.strip_suffix(" draft/multiline #test"))
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            sent,
+            vec![
+                format!("@label=1 BATCH +{} draft/multiline #test", reference),
+                format!("@batch={} PRIVMSG #test :line 0", reference),
+                format!("@batch={} PRIVMSG #test :line 1", reference),
+                format!("BATCH -{}", reference),
+                "PRIVMSG #test :line 2".to_string(),
+            ]
+        );
+
+        // a line longer than the budget, split into chunks that clients join back together,
+        // with more chunks than fit in one batch
+        let long: Vec<Line> = crate::split::split_line_concat("aaaa bbbb cccc dddd eeee ffff", 10)
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| Line {
+                prefix: "<bob> ".to_string(),
+                text,
+                suffix: " (d)".to_string(),
+                concat: i > 0,
+            })
+            .collect();
+        send_multiline(&client.sender(), "#test", &long, limits, Vec::new()).unwrap();
+
+        let sent = received(&mut rx, 5).await;
+        let reference = sent[0]
+            .strip_prefix("BATCH +")
+            .and_then(|s| s.strip_suffix(" draft/multiline #test"))
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            sent,
+            vec![
+                format!("BATCH +{} draft/multiline #test", reference),
+                format!("@batch={} PRIVMSG #test :<bob> aaaa bbbb", reference),
+                format!(
+                    "@batch={};draft/multiline-concat PRIVMSG #test : cccc dddd (d)",
+                    reference
+                ),
+                format!("BATCH -{}", reference),
+                // the rest of the line, sent alone, still says who wrote it
+                "PRIVMSG #test :<bob> eeee ffff (d)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_tags() {
+        let message: Message = "@label=12;msgid=abc;+flag :bridge!b@host PRIVMSG #test :hi\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(tag(&message, "msgid"), Some("abc"));
+        assert_eq!(tag(&message, "label"), Some("12"));
+        assert_eq!(tag(&message, "+flag"), None);
+        assert_eq!(tag(&message, "batch"), None);
+    }
+
+    #[tokio::test]
+    async fn no_multiline_without_the_capability() {
+        let (port, _rx) = mock_server("batch sasl").await;
+        let (_client, caps) = connect(port).await;
+
+        assert!(caps.enabled("batch"));
+        assert_eq!(caps.multiline(), None);
+    }
+
+    #[tokio::test]
+    async fn negotiation_ends_when_nothing_is_wanted() {
+        let (port, _rx) = mock_server("sasl").await;
+        let (_client, caps) = connect(port).await;
+
+        assert!(!caps.enabled("sasl"));
+    }
+}
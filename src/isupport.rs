@@ -0,0 +1,124 @@
+//! The parts of the server's `RPL_ISUPPORT` the bridge cares about.
+
+use irc::proto::Command;
+use irc::proto::Message;
+use irc::proto::Response;
+use tracing::debug;
+
+/// How the server folds the case of nicks and channel names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaseMapping {
+    Ascii,
+    /// `A-Z` plus `[]\~` map to `a-z` plus `{}|^`, the default when the server doesn't say
+    #[default]
+    Rfc1459,
+    /// like rfc1459, but without `~` and `^`
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            _ => None,
+        }
+    }
+
+    /// Folds a name to lowercase, so that two names the server considers equal compare equal.
+    pub fn normalize(self, name: &str) -> String {
+        name.chars()
+            .map(|c| match (self, c) {
+                (_, 'A'..='Z') => c.to_ascii_lowercase(),
+                (CaseMapping::Rfc1459 | CaseMapping::StrictRfc1459, '[') => '{',
+                (CaseMapping::Rfc1459 | CaseMapping::StrictRfc1459, ']') => '}',
+                (CaseMapping::Rfc1459 | CaseMapping::StrictRfc1459, '\\') => '|',
+                (CaseMapping::Rfc1459, '~') => '^',
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ISupport {
+    pub casemapping: CaseMapping,
+    /// characters a channel name can start with
+    pub chantypes: String,
+}
+
+impl Default for ISupport {
+    fn default() -> Self {
+        Self {
+            casemapping: CaseMapping::default(),
+            chantypes: "#&".to_string(),
+        }
+    }
+}
+
+impl ISupport {
+    /// Picks up the tokens from an `RPL_ISUPPORT` reply, returning true if the message was one.
+    pub fn handle(&mut self, message: &Message) -> bool {
+        let Command::Response(Response::RPL_ISUPPORT, args) = &message.command else {
+            return false;
+        };
+
+        // the first argument is our nick, the last one is "are supported by this server"
+        for token in args.iter().skip(1).take(args.len().saturating_sub(2)) {
+            match token.split_once('=') {
+                Some(("CASEMAPPING", value)) => match CaseMapping::parse(value) {
+                    Some(casemapping) => self.casemapping = casemapping,
+                    None => debug!(
+                        "unknown casemapping {}, keeping {:?}",
+                        value, self.casemapping
+                    ),
+                },
+                Some(("CHANTYPES", value)) => self.chantypes = value.to_string(),
+                _ => (),
+            }
+        }
+
+        true
+    }
+
+    pub fn is_channel(&self, target: &str) -> bool {
+        target.starts_with(|c| self.chantypes.contains(c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_only_folds_letters() {
+        assert_eq!(CaseMapping::Ascii.normalize("#Rust[]~"), "#rust[]~");
+    }
+
+    #[test]
+    fn rfc1459_folds_brackets() {
+        assert_eq!(CaseMapping::Rfc1459.normalize("#Foo[\\]~"), "#foo{|}^");
+    }
+
+    #[test]
+    fn strict_rfc1459_keeps_tilde() {
+        assert_eq!(
+            CaseMapping::StrictRfc1459.normalize("#Foo[\\]~"),
+            "#foo{|}~"
+        );
+    }
+
+    #[test]
+    fn parses_isupport() {
+        let message: Message = ":irc.test 005 bridge CHANTYPES=#+ CASEMAPPING=ascii NICKLEN=30 :are supported by this server\r\n"
+            .parse()
+            .unwrap();
+        let mut isupport = ISupport::default();
+
+        assert!(isupport.handle(&message));
+        assert_eq!(isupport.casemapping, CaseMapping::Ascii);
+        assert!(isupport.is_channel("+chan"));
+        assert!(!isupport.is_channel("&chan"));
+    }
+}
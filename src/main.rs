@@ -1,22 +1,74 @@
+mod attachments;
+mod avatars;
+mod backoff;
+mod ctcp;
+mod edits;
+mod formatting;
+mod highlights;
+mod ircv3;
+mod isupport;
+mod mentions;
+mod netsplit;
+mod presence;
+mod routes;
+mod split;
+mod store;
+
 use irc::proto::Command;
+use irc::proto::Prefix;
+use irc::proto::ChannelMode;
+use irc::proto::Mode;
+use irc::proto::message::Tag;
+use irc::proto::Response;
+use irc::client::data::AccessLevel;
+use backoff::Backoff;
+use ircv3::Capabilities;
+use ircv3::Line;
+use highlights::Ping;
+use isupport::CaseMapping;
+use isupport::ISupport;
+use mentions::Mention;
+use netsplit::Netsplits;
+use presence::Presence;
+use store::Store;
+use store::WebhookMessage;
+use routes::Routes;
 
 use poise::serenity_prelude as serenity;
+use serenity::Cache;
+use serenity::Channel;
 use serenity::Interaction;
+use serenity::Message;
+use serenity::MessageId;
+use serenity::MessageUpdateEvent;
 use serenity::Ready;
+use serenity::RoleId;
+use serenity::Timestamp;
 use serenity::UserId;
 use serde::Deserialize;
 use serenity::model::id::GuildId;
 use serenity::model::prelude::ChannelId;
 use serenity::model::prelude::ChannelType;
+use serenity::model::prelude::WebhookId;
 use serenity::{futures::StreamExt, http::Http, model::webhook::Webhook};
 use tokio::sync::RwLock;
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tracing::debug;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 type IrcClient = irc::client::Client;
+type IrcConfig = irc::client::data::Config;
 use anyhow::Result;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
@@ -36,21 +88,39 @@ async fn main() {
 async fn run() -> Result<()> {
     let config = try_read_config("config.toml").await?;
 
-    let intents = serenity::GatewayIntents::GUILD_WEBHOOKS;
+    // members are needed to turn IRC highlights into pings
+    let intents = serenity::GatewayIntents::GUILDS
+        | serenity::GatewayIntents::GUILD_MEMBERS
+        | serenity::GatewayIntents::GUILD_WEBHOOKS
+        | serenity::GatewayIntents::GUILD_MESSAGES
+        | serenity::GatewayIntents::MESSAGE_CONTENT;
+
+    if let Some(avatars) = &config.avatars {
+        let listen = avatars.listen;
+        tokio::spawn(async move {
+            if let Err(e) = avatars::serve(listen).await {
+                error!("avatar server stopped: {}", e);
+            }
+        });
+    }
 
     let (tx, _rx) = broadcast::channel(64);
 
     let mut handler = Handler {
         options: poise::FrameworkOptions {
-            commands: vec![write()],
+            commands: vec![write(), me()],
             ..Default::default()
         },
         data: Data {
             config: config.clone(),
             tx,
+            webhook_ids: Arc::new(RwLock::new(HashSet::new())),
+            store: Arc::new(Store::open(&config.database)?),
+            refresh: Arc::new(Notify::new()),
         },
         shard_manager: std::sync::Mutex::new(None),
-        bot_id: RwLock::new(None)
+        bot_id: RwLock::new(None),
+        irc_started: AtomicBool::new(false),
     };
 
     poise::set_qualified_names(&mut handler.options.commands);
@@ -67,141 +137,1107 @@ async fn run() -> Result<()> {
     Ok(())
 }
 
-async fn listen_irc(http: Arc<Http>, guild_id: u64, mut rx: broadcast::Receiver<CMessage>) -> Result<()> {
-    let guild = GuildId(guild_id);
+/// Discord-side state of the bridge, kept across IRC reconnects.
+struct DiscordState {
+    cache: Arc<Cache>,
+    routes: Routes,
+    webhooks: HashMap<ChannelId, Webhook>,
+    /// the "IRC is down" notices currently posted, removed once the connection is back
+    status_notices: Vec<Message>,
+    /// the last topic synced in either direction, so that a change isn't echoed back to the side it came from
+    topics: HashMap<ChannelId, String>,
+    /// topic edits still waiting on Discord's rate limit
+    topic_edits: HashMap<ChannelId, JoinHandle<()>>,
+    store: Arc<Store>,
+}
+
+async fn setup_discord(
+    http: &Arc<Http>,
+    cache: &Arc<Cache>,
+    store: &Arc<Store>,
+    config: &Config,
+    webhook_ids: &RwLock<HashSet<WebhookId>>,
+) -> Result<DiscordState> {
+    let guild = GuildId(config.guild_id);
 
-    let bridged_channels = get_bridged_channels(&http, &guild).await?;
+    let routes = get_bridged_channels(http, &guild, config).await?;
 
-    let bridge_webhooks = get_or_create_webhooks(&http, &bridged_channels).await?;
+    let webhooks = get_or_create_webhooks(http, &routes).await?;
+    *webhook_ids.write().await = webhooks.values().map(|h| h.id).collect();
 
-    let mut client = IrcClient::new("irc-config.toml").await?;
-    client.identify()?;
+    Ok(DiscordState {
+        cache: cache.clone(),
+        routes,
+        webhooks,
+        status_notices: Vec::new(),
+        topics: HashMap::new(),
+        topic_edits: HashMap::new(),
+        store: store.clone(),
+    })
+}
+
+/// State of a single IRC connection, started over on every reconnect.
+#[derive(Default)]
+struct IrcState {
+    caps: Capabilities,
+    isupport: ISupport,
+    presence: Presence,
+    netsplits: Netsplits,
+    /// channels known not to be `+t`, where the bridge can change the topic without ops
+    open_topics: HashSet<String>,
+    /// our own nick!user@host as the server shows it, needed to know how much text fits in a line
+    hostmask_len: Option<usize>,
+    /// Discord events for channels the bridge hasn't joined yet
+    pending: Vec<DiscordEvent>,
+}
+
+/// How many Discord events are kept while waiting for IRC channels to be joined.
+const MAX_PENDING_EVENTS: usize = 100;
+
+/// Runs a single IRC connection until it fails.
+///
+/// `connected` is set once the server accepted our registration.
+async fn listen_irc(
+    http: &Arc<Http>,
+    config: &Config,
+    rx: &mut broadcast::Receiver<DiscordEvent>,
+    refresh: &Notify,
+    webhook_ids: &RwLock<HashSet<WebhookId>>,
+    state: &mut DiscordState,
+    connected: &mut bool,
+) -> Result<()> {
+    let mut irc_config = IrcConfig::load("irc-config.toml")?;
+    for (_, channel) in state.routes.iter() {
+        if !irc_config.channels.iter().any(|c| c == channel) {
+            irc_config.channels.push(channel.to_string());
+        }
+    }
+    let mut client = IrcClient::from_config(irc_config.clone()).await?;
+    Capabilities::register(&client, &irc_config)?;
+    let mut irc = IrcState::default();
 
     let mut stream = client.stream()?;
     let sender = client.sender();
+    let mut netsplit_tick = tokio::time::interval(Duration::from_secs(1));
+    let mut prune_tick = tokio::time::interval(Duration::from_secs(60 * 60));
 
     loop {
         tokio::select! {
             s = stream.next() => {
-                if let Some(message) = s.transpose()? {
-                    match &message.command {
-                        Command::PRIVMSG(channel, text) => {
-                            let hook = get_correct_webhook(&channel, &bridge_webhooks).await?;
-                            if let Some(h) = hook {
-                                let name = message.source_nickname().unwrap_or("null");
-                                h.execute(&http, false, |m| {
-                                    m.username(name)
-                                        .content(text)
-                                        .avatar_url(format!("https://singlecolorimage.com/get/{:06x}/1x1", get_color_from_name(name)))
-                                })
-                                .await?;
-                            debug!("message received in {}: {}", channel, text);
+                let Some(message) = s.transpose()? else {
+                    return Ok(());
+                };
+
+                irc.caps.handle(&message, &sender)?;
+                if irc.isupport.handle(&message) {
+                    state.routes.set_casemapping(irc.isupport.casemapping);
+                    irc.presence.set_casemapping(irc.isupport.casemapping);
+                    irc.netsplits.set_casemapping(irc.isupport.casemapping);
+                }
+                if let Some(Prefix::Nickname(nick, user, host)) = &message.prefix {
+                    if nick == client.current_nickname() && !host.is_empty() {
+                        irc.hostmask_len = Some(nick.len() + 1 + user.len() + 1 + host.len());
+                    }
+                }
+                if let Command::Response(Response::RPL_WELCOME, _) = message.command {
+                    info!("IRC connection established");
+                    *connected = true;
+                    clear_status_notices(http, state).await;
+                }
+
+                if let Err(e) = ctcp::reply(&sender, &message) {
+                    error!("failed to answer CTCP: {}", e);
+                }
+                track_topic_lock(&mut irc, &sender, client.current_nickname(), &message)?;
+                if let Err(e) = handle_irc_message(http, config, state, &mut irc, client.current_nickname(), &message).await {
+                    error!("failed to relay IRC message: {}", e);
+                }
+                if !irc.pending.is_empty() {
+                    relay_pending_events(config, &client, &mut irc, state);
+                }
+            }
+
+            _ = netsplit_tick.tick() => {
+                let lines = irc.netsplits.flush().into_iter().map(|(id, text)| (id, membership_line(text, None)));
+                if let Err(e) = post_membership_lines(http, config, state, lines).await {
+                    error!("failed to relay netsplit: {}", e);
+                }
+            }
+
+            _ = prune_tick.tick() => {
+                match state.store.prune(Duration::from_secs(config.retention_days * 24 * 60 * 60)) {
+                    Ok(0) => (),
+                    Ok(pruned) => debug!("pruned {} old messages from the database", pruned),
+                    Err(e) => error!("failed to prune the database: {}", e),
+                }
+            }
+
+            _ = refresh.notified() => {
+                // Discord reconnected, channels or webhooks may have changed in the meantime
+                match setup_discord(http, &state.cache, &state.store, config, webhook_ids).await {
+                    Ok(mut fresh) => {
+                        fresh.routes.set_casemapping(irc.isupport.casemapping);
+                        for (id, channel) in fresh.routes.iter() {
+                            if state.routes.irc_channel(id).is_none() {
+                                sender.send_join(channel)?;
                             }
                         }
-                        Command::TOPIC(channel, text) => {
-                            if let Some(chan) = get_correct_channel(&channel, &bridged_channels).await? {
-                                chan.edit(&http, |f| f.topic(text.as_ref().map_or("", |x| x.as_str())))
-                                    .await?;
-                            }
+                        state.routes = fresh.routes;
+                        state.webhooks = fresh.webhooks;
+                        info!("Refreshed bridged Discord channels");
+                    }
+                    Err(e) => error!("failed to refresh bridged Discord channels: {}", e),
+                }
+            }
+
+            // before registration and the JOIN, the server would reject what we send;
+            // the sender lives as long as the bot, so the channel is never closed
+            result @ (Ok(_) | Err(RecvError::Lagged(_))) = rx.recv(), if *connected => {
+                let event = match result {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(n)) => {
+                        // the oldest events were overwritten while IRC was slow or reconnecting
+                        error!("{} Discord events were dropped before they reached IRC", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => continue,
+                };
+                match state.routes.irc_channel(event.channel()) {
+                    Some(target) if !irc.presence.is_in(target) => {
+                        if irc.pending.len() >= MAX_PENDING_EVENTS {
+                            error!("too many Discord events waiting for IRC channels to be joined, dropping the oldest");
+                            irc.pending.remove(0);
                         }
-                        _ => (),
+                        irc.pending.push(event);
                     }
+                    _ => relay_discord_event(config, &client, &irc, state, event),
                 }
+            }
+        }
+    }
+}
+
+/// Sends what happened on Discord to IRC.
+fn relay_discord_event(config: &Config, client: &IrcClient, irc: &IrcState, state: &mut DiscordState, event: DiscordEvent) {
+    let sender = client.sender();
+    let hostmask_len = irc.hostmask_len
+        .unwrap_or_else(|| split::fallback_hostmask_len(client.current_nickname()));
 
+    match event {
+        DiscordEvent::Message(msg) => {
+            if let Some(target) = state.routes.irc_channel(msg.channel) {
+                let reply = match &msg.reply {
+                    Some(reply) => state.store.msgid(reply.id).unwrap_or_else(|e| {
+                        error!("failed to look up replied message: {}", e);
+                        None
+                    }),
+                    None => None,
+                };
+                if let Err(e) = state.store.sent_to_irc(msg.id, msg.channel, msg.author_id, &msg.message, &msg.attachments) {
+                    error!("failed to record Discord message: {}", e);
+                }
+                match send_to_irc(config, &sender, &irc.caps, hostmask_len, target, &msg, reply.as_deref()) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        if let Err(e) = state.store.set_split(msg.id) {
+                            error!("failed to record Discord message: {}", e);
+                        }
+                    }
+                    Err(e) => error!("failed to relay Discord message: {}", e),
+                }
+            } else {
+                // channel not bridged
+            }
+        }
+        DiscordEvent::Edit(msg) => {
+            if let Some(target) = state.routes.irc_channel(msg.channel) {
+                if let Err(e) = send_edit_to_irc(config, &sender, &irc.caps, &state.store, hostmask_len, target, msg) {
+                    error!("failed to relay Discord edit: {}", e);
+                }
             }
-    
-            Ok(msg) = rx.recv() => {
-                if let Some(c) = bridged_channels.get(&msg.channel) {
-                    debug!("sending \"{}\" in #{}", &msg.message, &c);
-                    sender.send_privmsg(format!("#{}", &c), &msg.message)?;
-                } else {
-                    // channel not bridged
+        }
+        DiscordEvent::Delete { channel, messages } => {
+            if let Some(target) = state.routes.irc_channel(channel) {
+                if let Err(e) = send_delete_to_irc(config, &sender, &irc.caps, &state.cache, &state.store, target, &messages) {
+                    error!("failed to relay Discord deletion: {}", e);
                 }
             }
         }
+        DiscordEvent::Topic { channel, topic } => {
+            if let Err(e) = send_topic_to_irc(client, irc, state, hostmask_len, channel, topic) {
+                error!("failed to relay Discord topic: {}", e);
+            }
+        }
     }
 }
 
-fn get_color_from_name(name: &str) -> u32 {
-    crc32fast::hash(name.as_bytes()) >> 8
+/// Relays the Discord events that were waiting for their IRC channel to be joined.
+fn relay_pending_events(config: &Config, client: &IrcClient, irc: &mut IrcState, state: &mut DiscordState) {
+    let (ready, waiting) = std::mem::take(&mut irc.pending).into_iter().partition(|event: &DiscordEvent| {
+        state
+            .routes
+            .irc_channel(event.channel())
+            .is_none_or(|target| irc.presence.is_in(target))
+    });
+    irc.pending = waiting;
+    for event in ready {
+        relay_discord_event(config, client, irc, state, event);
+    }
 }
 
-async fn get_correct_webhook<'a>(
-    channel: &str,
-    bridge_webhooks: &'a HashMap<String, Webhook>,
-) -> Result<Option<&'a Webhook>> {
-    for b in bridge_webhooks {
-        let name = &channel[1..];
+async fn handle_irc_message(
+    http: &Arc<Http>,
+    config: &Config,
+    state: &mut DiscordState,
+    irc: &mut IrcState,
+    own_nick: &str,
+    message: &irc::proto::Message,
+) -> Result<()> {
+    let lines = membership_lines(config, &state.routes, irc, own_nick, message);
+    post_membership_lines(http, config, state, lines).await?;
 
-        if b.0 == name {
-            return Ok(Some(b.1));
+    // echoes of our own messages carry the label they were sent with, the Discord message's ID
+    if let (Some(label), Some(msgid)) = (ircv3::tag(message, "label"), ircv3::tag(message, "msgid")) {
+        if let Ok(id) = label.parse() {
+            state.store.set_msgid(MessageId(id), msgid)?;
         }
     }
 
-    Ok(None)
+    let isupport = &irc.isupport;
+    match &message.command {
+        // with echo-message, what the bridge sends comes back to it
+        Command::PRIVMSG(_, _) | Command::NOTICE(_, _) if message.source_nickname() == Some(own_nick) => (),
+        Command::PRIVMSG(channel, text) if isupport.is_channel(channel) => {
+            let name = message.source_nickname().unwrap_or("null");
+            irc.presence.spoke(channel, name);
+            let hook = get_correct_webhook(channel, &state.routes, &state.webhooks);
+            if let Some(h) = hook {
+                let resolve = |nick: &str| {
+                    find_member(&state.cache, config, isupport.casemapping, nick)
+                        .map(Ping::User)
+                        .or_else(|| find_role(&state.cache, config, nick).map(Ping::Role))
+                };
+                let (content, pings) = match ctcp::action(text) {
+                    Some(action) => {
+                        let (action, pings) = highlights::ping(action.trim(), resolve, formatting::irc_to_discord);
+                        (format!("_\\* {} {}_", formatting::irc_to_discord(name), action), pings)
+                    }
+                    // other CTCP is meant for clients, not people
+                    None if ctcp::parse(text).is_some() => return Ok(()),
+                    None => highlights::ping(text, resolve, formatting::irc_to_discord),
+                };
+                let sent = execute_webhook(http, config, h, name, content, &pings).await?;
+                let webhook = WebhookMessage { channel: sent.channel_id, message: sent.id };
+                state.store.relayed_from_irc(webhook, ircv3::tag(message, "msgid"), name)?;
+                debug!("message received in {}: {}", channel, text);
+            }
+        }
+        Command::NOTICE(channel, text) if isupport.is_channel(channel) => {
+            // CTCP replies come as notices too
+            if ctcp::parse(text).is_some() {
+                return Ok(());
+            }
+            let Some(id) = get_correct_channel(channel, &state.routes) else {
+                return Ok(());
+            };
+            let name = match &message.prefix {
+                Some(Prefix::Nickname(nick, _, _)) => {
+                    irc.presence.spoke(channel, nick);
+                    nick.as_str()
+                }
+                Some(Prefix::ServerName(server)) => server.as_str(),
+                None => "null",
+            };
+
+            let content = notice_to_discord(config, isupport.casemapping, id, name, text);
+            if let (Some(content), Some(h)) = (content, state.webhooks.get(&id)) {
+                let sent = execute_webhook(http, config, h, name, content, &[]).await?;
+                let webhook = WebhookMessage { channel: sent.channel_id, message: sent.id };
+                state.store.relayed_from_irc(webhook, ircv3::tag(message, "msgid"), name)?;
+                debug!("notice received in {}: {}", channel, text);
+            }
+        }
+        // draft/message-redaction, which the irc crate doesn't know about
+        Command::Raw(command, args) if command == "REDACT" => {
+            if let [channel, msgid, ..] = args.as_slice() {
+                redact_on_discord(http, state, channel, msgid).await?;
+            }
+        }
+        // changes made by the bridge itself are already on Discord
+        Command::TOPIC(_, _) if message.source_nickname() == Some(own_nick) => (),
+        Command::TOPIC(channel, Some(text)) => {
+            sync_topic_to_discord(http, state, channel, text);
+        }
+        // sent on join, so topics are in sync from the start
+        Command::Response(Response::RPL_TOPIC, args) => {
+            if let [_, channel, text] = args.as_slice() {
+                sync_topic_to_discord(http, state, channel, text);
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Deletes the webhook message a redacted IRC message was relayed as.
+async fn redact_on_discord(http: &Arc<Http>, state: &mut DiscordState, channel: &str, msgid: &str) -> Result<()> {
+    let Some(webhook) = state.store.webhook_message(msgid)? else {
+        return Ok(());
+    };
+    // the msgid should belong to the channel it is redacted in
+    if get_correct_channel(channel, &state.routes) != Some(webhook.channel) {
+        return Ok(());
+    }
+    if let Some(hook) = state.webhooks.get(&webhook.channel) {
+        debug!("deleting message {} redacted in {}", webhook.message.0, channel);
+        hook.delete_message(http, webhook.message).await?;
+        state.store.forget(webhook.message)?;
+    }
+
+    Ok(())
+}
+
+/// Renders an IRC channel notice for Discord, or returns `None` if the channel's notice policy drops it.
+fn notice_to_discord(config: &Config, casemapping: CaseMapping, channel: ChannelId, sender: &str, text: &str) -> Option<String> {
+    let forward = match config.notice_policy(channel) {
+        NoticePolicy::All => true,
+        NoticePolicy::None => false,
+        NoticePolicy::Services => {
+            let sender = casemapping.normalize(sender);
+            config.service_nicks.iter().any(|s| casemapping.normalize(s) == sender)
+        }
+    };
+
+    forward.then(|| format!("> **[notice]** {}", formatting::irc_to_discord(text)))
 }
 
-async fn get_correct_channel<'a>(
+/// Keeps track of who is in which channel, and renders the joins, parts, quits, kicks and nick changes
+/// that the channels' `membership` setting wants shown on Discord.
+fn membership_lines(
+    config: &Config,
+    routes: &Routes,
+    irc: &mut IrcState,
+    own_nick: &str,
+    message: &irc::proto::Message,
+) -> Vec<(ChannelId, String)> {
+    if let Command::Response(Response::RPL_NAMREPLY, args) = &message.command {
+        // our nick, the channel's visibility, the channel, then the names
+        if let [_, _, channel, names] = args.as_slice() {
+            irc.presence.names(channel, names);
+        }
+        return Vec::new();
+    }
+
+    let Some(nick) = message.source_nickname() else {
+        return Vec::new();
+    };
+    let casemapping = irc.isupport.casemapping;
+    let is_me = |nick: &str| casemapping.normalize(nick) == casemapping.normalize(own_nick);
+    let presence = &mut irc.presence;
+    let netsplits = &mut irc.netsplits;
+    let escaped = formatting::irc_to_discord(nick);
+
+    match &message.command {
+        Command::JOIN(channel, _, _) if is_me(nick) => {
+            // the member list follows in RPL_NAMREPLY
+            presence.enter(channel);
+            Vec::new()
+        }
+        Command::JOIN(channel, _, _) => {
+            presence.join(channel, nick);
+            if let Some(id) = routes.discord_channel(channel) {
+                if netsplits.join(nick, id) {
+                    return Vec::new();
+                }
+            }
+            membership_target(config, routes, presence, channel, nick)
+                .map(|id| (id, membership_line(format!("→ {} joined", escaped), None)))
+                .into_iter()
+                .collect()
+        }
+        Command::PART(channel, _) if is_me(nick) => {
+            presence.leave(channel);
+            Vec::new()
+        }
+        Command::PART(channel, reason) => {
+            presence.part(channel, nick);
+            membership_target(config, routes, presence, channel, nick)
+                .map(|id| (id, membership_line(format!("← {} left", escaped), reason.as_deref())))
+                .into_iter()
+                .collect()
+        }
+        Command::KICK(channel, kicked, _) if is_me(kicked) => {
+            presence.leave(channel);
+            Vec::new()
+        }
+        Command::KICK(channel, kicked, reason) => {
+            presence.part(channel, kicked);
+            let event = format!("← {} was kicked by {}", formatting::irc_to_discord(kicked), escaped);
+            membership_target(config, routes, presence, channel, kicked)
+                .map(|id| (id, membership_line(event, reason.as_deref())))
+                .into_iter()
+                .collect()
+        }
+        Command::QUIT(_) if is_me(nick) => Vec::new(),
+        Command::QUIT(reason) => {
+            let channels = presence.quit(nick);
+            if let Some(servers) = reason.as_deref().and_then(netsplit::split_servers) {
+                // summed up per channel once the split is over, see `Netsplits::flush`
+                let ids: Vec<_> = channels
+                    .iter()
+                    .filter_map(|channel| routes.discord_channel(channel))
+                    .filter(|id| config.membership(*id).0 != Membership::None)
+                    .collect();
+                netsplits.quit(servers, nick, &ids);
+                return Vec::new();
+            }
+            channels
+                .iter()
+                .filter_map(|channel| membership_target(config, routes, presence, channel, nick))
+                .map(|id| (id, membership_line(format!("← {} quit", escaped), reason.as_deref())))
+                .collect()
+        }
+        Command::NICK(new) => {
+            let channels = presence.nick(nick, new);
+            // the irc crate already switched our own nick by the time it hands us the message
+            if is_me(nick) || is_me(new) {
+                return Vec::new();
+            }
+            let event = format!("{} is now known as {}", escaped, formatting::irc_to_discord(new));
+            channels
+                .iter()
+                .filter_map(|channel| membership_target(config, routes, presence, channel, new))
+                .map(|id| (id, membership_line(event.clone(), None)))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Sets an IRC topic as the Discord channel's topic, unless it already is.
+///
+/// Discord only allows a couple of topic edits per channel every ten minutes, and serenity waits out the rate limit,
+/// so the edit runs in its own task and a newer topic replaces one still waiting.
+fn sync_topic_to_discord(http: &Arc<Http>, state: &mut DiscordState, channel: &str, text: &str) {
+    let Some(chan) = get_correct_channel(channel, &state.routes) else {
+        return;
+    };
+    let topic = formatting::strip_irc_formatting(text);
+    if state.topics.get(&chan) == Some(&topic) {
+        return;
+    }
+    state.topics.insert(chan, topic.clone());
+
+    // RPL_TOPIC on every connect mostly repeats what Discord already has
+    let current = state.cache.guild_channel(chan).and_then(|c| c.topic).unwrap_or_default();
+    if current == topic {
+        return;
+    }
+
+    let http = http.clone();
+    let edit = tokio::spawn(async move {
+        if let Err(e) = chan.edit(&http, |f| f.topic(topic)).await {
+            error!("failed to set the topic of {}: {}", chan.0, e);
+        }
+    });
+    if let Some(previous) = state.topic_edits.insert(chan, edit) {
+        previous.abort();
+    }
+}
+
+/// Sets a Discord topic change as the IRC channel's topic, if the bridge is allowed to.
+fn send_topic_to_irc(
+    client: &IrcClient,
+    irc: &IrcState,
+    state: &mut DiscordState,
+    hostmask_len: usize,
+    channel: ChannelId,
+    topic: String,
+) -> Result<()> {
+    let Some(target) = state.routes.irc_channel(channel) else {
+        return Ok(());
+    };
+    // our own edit from `sync_topic_to_discord` coming back, or nothing new
+    if state.topics.get(&channel) == Some(&topic) {
+        return Ok(());
+    }
+    if !can_set_topic(client, irc, target) {
+        debug!("not allowed to change the topic of {}", target);
+        return Ok(());
+    }
+
+    // IRC topics are a single line, and "TOPIC" is shorter than "PRIVMSG"
+    let line = topic.split_whitespace().collect::<Vec<_>>().join(" ");
+    let line = split::split_line(&line, split::privmsg_budget(hostmask_len, target))
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    client.send_topic(target, line)?;
+    state.topics.insert(channel, topic);
+
+    Ok(())
+}
+
+/// Keeps `IrcState::open_topics` up to date, asking for the modes of every channel the bridge joins.
+fn track_topic_lock(
+    irc: &mut IrcState,
+    sender: &irc::client::Sender,
+    own_nick: &str,
+    message: &irc::proto::Message,
+) -> Result<()> {
+    let casemapping = irc.isupport.casemapping;
+    match &message.command {
+        Command::JOIN(channel, _, _) if message.source_nickname() == Some(own_nick) => {
+            sender.send_mode::<_, ChannelMode>(channel, &[])?;
+        }
+        // our nick, the channel, then its modes
+        Command::Response(Response::RPL_CHANNELMODEIS, args) if args.len() >= 3 => {
+            let channel = casemapping.normalize(&args[1]);
+            if args[2].contains('t') {
+                irc.open_topics.remove(&channel);
+            } else {
+                irc.open_topics.insert(channel);
+            }
+        }
+        Command::ChannelMODE(channel, modes) => {
+            for mode in modes {
+                match mode {
+                    Mode::Plus(ChannelMode::ProtectedTopic, _) => {
+                        irc.open_topics.remove(&casemapping.normalize(channel));
+                    }
+                    Mode::Minus(ChannelMode::ProtectedTopic, _) => {
+                        irc.open_topics.insert(casemapping.normalize(channel));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Whether the bridge may change a channel's topic, because it has ops there or the channel isn't `+t`.
+fn can_set_topic(client: &IrcClient, irc: &IrcState, channel: &str) -> bool {
+    let casemapping = irc.isupport.casemapping;
+    let channel = casemapping.normalize(channel);
+    if irc.open_topics.contains(&channel) {
+        return true;
+    }
+
+    // the irc crate keys its member lists by the channel name as the server spelled it
+    let nick = casemapping.normalize(client.current_nickname());
+    client
+        .list_channels()
+        .unwrap_or_default()
+        .iter()
+        .filter(|c| casemapping.normalize(c) == channel)
+        .filter_map(|c| client.list_users(c))
+        .flatten()
+        .filter(|user| casemapping.normalize(user.get_nickname()) == nick)
+        .any(|user| {
+            user.access_levels().iter().any(|level| {
+                matches!(
+                    level,
+                    AccessLevel::Owner | AccessLevel::Admin | AccessLevel::Oper | AccessLevel::HalfOp
+                )
+            })
+        })
+}
+
+/// Finds the Discord member an IRC nick stands for.
+///
+/// Nicks linked in the config come first, compared the way the server compares nicks,
+/// then guild nicknames and usernames, as long as only one member has them.
+fn find_member(cache: &Cache, config: &Config, casemapping: CaseMapping, nick: &str) -> Option<UserId> {
+    let folded = casemapping.normalize(nick);
+    if let Some((_, id)) = config.linked_nicks.iter().find(|(n, _)| casemapping.normalize(n) == folded) {
+        return Some(UserId(*id));
+    }
+
+    let nick = nick.to_lowercase();
+    let unique = |ids: Vec<UserId>| match ids.as_slice() {
+        [id] => Some(*id),
+        _ => None,
+    };
+    cache
+        .guild_field(GuildId(config.guild_id), |guild| {
+            let members = || guild.members.values().filter(|m| !m.user.bot);
+            unique(
+                members()
+                    .filter(|m| m.nick.as_ref().is_some_and(|n| n.to_lowercase() == nick))
+                    .map(|m| m.user.id)
+                    .collect(),
+            )
+            .or_else(|| {
+                unique(
+                    members()
+                        .filter(|m| m.user.name.to_lowercase() == nick)
+                        .map(|m| m.user.id)
+                        .collect(),
+                )
+            })
+        })
+        .flatten()
+}
+
+/// Finds the role an IRC `@name` stands for, among the ones IRC is allowed to mention.
+fn find_role(cache: &Cache, config: &Config, name: &str) -> Option<RoleId> {
+    let guild = GuildId(config.guild_id);
+    config
+        .mentionable_roles
+        .iter()
+        .map(|id| RoleId(*id))
+        .find(|id| cache.role(guild, *id).is_some_and(|role| role.name.eq_ignore_ascii_case(name)))
+}
+
+/// Posts membership lines through the channels' webhooks, under the IRC channel's name.
+async fn post_membership_lines(
+    http: &Arc<Http>,
+    config: &Config,
+    state: &DiscordState,
+    lines: impl IntoIterator<Item = (ChannelId, String)>,
+) -> Result<()> {
+    for (id, line) in lines {
+        if let (Some(h), Some(channel)) = (state.webhooks.get(&id), state.routes.irc_channel(id)) {
+            execute_webhook(http, config, h, channel, line, &[]).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The Discord channel a membership change of `nick` in an IRC channel is shown in, if it is shown at all.
+fn membership_target(
+    config: &Config,
+    routes: &Routes,
+    presence: &Presence,
     channel: &str,
-    bridge_webhooks: &'a HashMap<ChannelId, String>,
-) -> Result<Option<&'a ChannelId>> {
-    for b in bridge_webhooks {
-        let name = &channel[1..];
-        info!("{}, {}", name, b.1);
+    nick: &str,
+) -> Option<ChannelId> {
+    let id = routes.discord_channel(channel)?;
+    let show = match config.membership(id) {
+        (Membership::All, _) => true,
+        (Membership::None, _) => false,
+        (Membership::Active, window) => presence.spoke_within(channel, nick, window),
+    };
+    show.then_some(id)
+}
+
+/// Renders a membership change as a compact italic line, followed by its reason if there is one.
+fn membership_line(event: String, reason: Option<&str>) -> String {
+    match reason.map(str::trim).filter(|r| !r.is_empty()) {
+        Some(reason) => format!("_{} ({})_", event, formatting::irc_to_discord(reason)),
+        None => format!("_{}_", event),
+    }
+}
+
+/// Posts on Discord through a bridge webhook, under the IRC user's name.
+///
+/// Only the users and roles in `pings` are notified, whatever else the text mentions,
+/// so nobody on IRC can ping `@everyone` or `@here` through the bridge.
+async fn execute_webhook(
+    http: &Arc<Http>,
+    config: &Config,
+    hook: &Webhook,
+    name: &str,
+    content: String,
+    pings: &[Ping],
+) -> Result<Message> {
+    let users = pings.iter().filter_map(|p| match p {
+        Ping::User(id) => Some(*id),
+        Ping::Role(_) => None,
+    });
+    let roles = pings.iter().filter_map(|p| match p {
+        Ping::Role(id) => Some(*id),
+        Ping::User(_) => None,
+    });
+    let sent = hook.execute(http, true, |m| {
+        m.username(name)
+            .content(content)
+            .allowed_mentions(|a| a.empty_parse().users(users).roles(roles));
+        if let Some(avatars) = &config.avatars {
+            m.avatar_url(avatars::url(&avatars.public_url, name));
+        }
+        m
+    })
+    .await?;
+
+    sent.ok_or_else(|| anyhow::anyhow!("webhook execution returned no message"))
+}
+
+/// Sends a Discord message to IRC, returning whether it went out as a single PRIVMSG or batch, one msgid for all of it.
+fn send_to_irc(
+    config: &Config,
+    sender: &irc::client::Sender,
+    caps: &Capabilities,
+    hostmask_len: usize,
+    target: &str,
+    msg: &CMessage,
+    reply: Option<&str>,
+) -> Result<bool> {
+    let mut tags = Vec::new();
+    // a correction's echo would replace the original's msgid
+    if caps.labels_echoed() && !msg.edit {
+        tags.push(Tag("label".to_string(), Some(msg.id.to_string())));
+    }
+    if let (Some(msgid), true) = (reply, caps.enabled("message-tags")) {
+        tags.push(Tag("+draft/reply".to_string(), Some(msgid.to_string())));
+    }
+    // only the first line or batch carries the tags
+    let mut tags = Some(tags).filter(|t| !t.is_empty());
+
+    // CTCP actions can't be batched
+    let batch = caps.multiline().filter(|_| !msg.action);
+    let lines = format_irc_lines(config, msg, line_budget(hostmask_len, target, msg), batch.is_some());
+    let (sent, truncated) = truncate_lines(config, &lines);
 
-        if b.1 == name {
-            return Ok(Some(b.0));
+    let messages = match batch {
+        _ if msg.action => {
+            for line in sent {
+                let text = line.full();
+                debug!("sending action \"{}\" in {}", &text, &target);
+                sender.send(privmsg(target, ctcp::make_action(&text), tags.take()))?;
+            }
+            sent.len()
+        }
+        Some(limits) if sent.len() > 1 => {
+            debug!("sending {} lines as a batch in {}", sent.len(), &target);
+            ircv3::send_multiline(sender, target, sent, limits, tags.take().unwrap_or_default())?
+        }
+        _ => {
+            for line in sent {
+                let text = line.full();
+                debug!("sending \"{}\" in {}", &text, &target);
+                sender.send(privmsg(target, text, tags.take()))?;
+            }
+            sent.len()
+        }
+    };
+    if let Some(notice) = truncated {
+        sender.send_notice(target, notice)?;
+    }
+
+    Ok(messages == 1)
+}
+
+/// Bytes a rendered line can take up in a PRIVMSG, leaving room for the CTCP wrapping of actions.
+fn line_budget(hostmask_len: usize, target: &str, msg: &CMessage) -> usize {
+    let budget = split::privmsg_budget(hostmask_len, target);
+    if msg.action {
+        budget.saturating_sub(ctcp::ACTION_OVERHEAD)
+    } else {
+        budget
+    }
+}
+
+/// Keeps the first `max_lines` lines, along with a notice saying how many were left out.
+fn truncate_lines<'a>(config: &Config, lines: &'a [Line]) -> (&'a [Line], Option<String>) {
+    if lines.len() <= config.max_lines {
+        return (lines, None);
+    }
+    let skipped = lines.len() - config.max_lines;
+    (&lines[..config.max_lines], Some(format!("[message truncated, {} more lines]", skipped)))
+}
+
+/// Tells every bridged Discord channel that IRC is unreachable, unless the notice is disabled or already up.
+async fn post_status_notices(http: &Arc<Http>, config: &Config, state: &mut DiscordState) {
+    if config.irc_down_notice.is_empty() || !state.status_notices.is_empty() {
+        return;
+    }
+
+    for (channel, _) in state.routes.iter() {
+        match channel.say(http, &config.irc_down_notice).await {
+            Ok(notice) => state.status_notices.push(notice),
+            Err(e) => error!("failed to post IRC status notice in {}: {}", channel.0, e),
+        }
+    }
+}
+
+async fn clear_status_notices(http: &Arc<Http>, state: &mut DiscordState) {
+    for notice in state.status_notices.drain(..) {
+        if let Err(e) = notice.delete(http).await {
+            error!("failed to remove IRC status notice in {}: {}", notice.channel_id.0, e);
+        }
+    }
+}
+
+/// Sends a Discord edit to IRC as a correction, if the edited message was sent there.
+fn send_edit_to_irc(
+    config: &Config,
+    sender: &irc::client::Sender,
+    caps: &Capabilities,
+    store: &Store,
+    hostmask_len: usize,
+    target: &str,
+    mut msg: CMessage,
+) -> Result<()> {
+    let Some(old) = store.sent_content(msg.id)? else {
+        return Ok(());
+    };
+    // edits that only added embeds have no text, just the embeds
+    if msg.message.is_empty() {
+        // every update carries all of the message's embeds, only the new ones are worth sending
+        let sent = store.sent_attachments(msg.id)?;
+        msg.attachments.retain(|line| !sent.contains(line));
+        if msg.attachments.is_empty() {
+            return Ok(());
+        }
+        store.add_attachments(msg.id, &msg.attachments)?;
+    } else {
+        if msg.message == old {
+            return Ok(());
         }
+        store.set_content(msg.id, &msg.message)?;
+        msg.message = edits::correction(&old, &msg.message);
     }
+    msg.action = false;
 
-    Ok(None)
+    send_to_irc(config, sender, caps, hostmask_len, target, &msg, None)?;
+    // the correction has a msgid of its own, which is never recorded
+    store.set_split(msg.id)
 }
 
+/// Tells IRC that Discord messages sent there were deleted, by redacting them if the server supports that.
+///
+/// The ones that can't be redacted, or only partly, share a single notice, so that a purge doesn't flood the channel.
+fn send_delete_to_irc(
+    config: &Config,
+    sender: &irc::client::Sender,
+    caps: &Capabilities,
+    cache: &Cache,
+    store: &Store,
+    target: &str,
+    messages: &[MessageId],
+) -> Result<()> {
+    let redact = caps.enabled("draft/message-redaction");
+    let mut unredacted = Vec::new();
+    for &message in messages {
+        let Some(sent) = store.sent_message(message)? else {
+            continue;
+        };
+        store.forget(message)?;
+
+        match sent.msgid {
+            Some(msgid) if redact => {
+                debug!("redacting {} in {}", msgid, target);
+                sender.send(Command::Raw("REDACT".to_string(), vec![target.to_string(), msgid]))?;
+                // the rest of it is still there, the notice tells IRC about that
+                if sent.split {
+                    unredacted.push(sent.author);
+                }
+            }
+            _ => unredacted.push(sent.author),
+        }
+    }
+
+    if let Some(notice) = delete_notice(config, cache, &unredacted) {
+        sender.send_notice(target, notice)?;
+    }
+
+    Ok(())
+}
+
+/// Renders the notice for deleted messages by these authors, if there are any and the notice isn't disabled.
+fn delete_notice(config: &Config, cache: &Cache, authors: &[UserId]) -> Option<String> {
+    let notice = match authors {
+        [] => return None,
+        [author] => {
+            let name = cache.user(*author).map_or_else(|| author.to_string(), |u| u.name);
+            let nick = cache
+                .member(GuildId(config.guild_id), *author)
+                .and_then(|m| m.nick)
+                .unwrap_or_else(|| name.clone());
+            fill_author(config, &config.delete_notice, &nick, &name, *author)
+        }
+        _ => config.bulk_delete_notice.replace("{count}", &authors.len().to_string()),
+    };
+
+    Some(notice).filter(|n| !n.is_empty())
+}
+
+fn privmsg(target: &str, text: String, tags: Option<Vec<Tag>>) -> irc::proto::Message {
+    irc::proto::Message {
+        tags,
+        prefix: None,
+        command: Command::PRIVMSG(target.to_string(), text),
+    }
+}
+
+/// Renders a Discord message as IRC lines through the configured template.
+///
+/// Every line of text gets its own IRC line, and lines longer than `max_bytes` are split further.
+/// With `concat`, the pieces of a split line are meant to be joined back in a multiline batch,
+/// where `ircv3::batch_texts` leaves out the template's prefix and suffix between them.
+fn format_irc_lines(config: &Config, msg: &CMessage, max_bytes: usize, concat: bool) -> Vec<Line> {
+    let template = if msg.action {
+        &config.action_template
+    } else {
+        &config.message_template
+    };
+    // split before the author is filled in, whose names could contain `{text}` themselves
+    let (before, after) = template.split_once("{text}").unwrap_or((template, ""));
+    let nick = msg.author_nick.as_deref().unwrap_or(&msg.author_name);
+    let prefix = fill_author(config, before, nick, &msg.author_name, msg.author_id);
+    let suffix = fill_author(config, after, nick, &msg.author_name, msg.author_id);
+
+    let text_bytes = max_bytes.saturating_sub(prefix.len() + suffix.len());
+
+    let mut text = formatting::discord_to_irc(&msg.message);
+    if let Some(reply) = &msg.reply {
+        text = format!("{} {}", reply_context(config, reply), text);
+    }
+    let lines = text
+        .lines()
+        .chain(msg.attachments.iter().map(String::as_str))
+        .filter(|l| !l.trim().is_empty());
+    let mut out = Vec::new();
+    for line in lines {
+        let chunks = if concat {
+            split::split_line_concat(line, text_bytes)
+        } else {
+            split::split_line(line, text_bytes)
+        };
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            out.push(Line {
+                prefix: prefix.clone(),
+                text: chunk,
+                suffix: suffix.clone(),
+                concat: concat && i > 0,
+            });
+        }
+    }
+    out
+}
+
+/// Fills in a template's `{nick}`, `{name}` and `{id}` placeholders for a Discord user.
+///
+/// This is a single pass over the template, so placeholders inside the names are left as they are.
+fn fill_author(config: &Config, template: &str, nick: &str, name: &str, id: UserId) -> String {
+    let (nick, name) = if config.zero_width_nicks {
+        (break_highlight(nick), break_highlight(name))
+    } else {
+        (nick.to_string(), name.to_string())
+    };
+    let id = id.0.to_string();
+    let values = [("{nick}", &nick), ("{name}", &name), ("{id}", &id)];
+
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match values.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                out.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Renders what a message replies to, like `(re alice: "the start of her message…")`.
+fn reply_context(config: &Config, reply: &Reply) -> String {
+    let author = if config.zero_width_nicks {
+        break_highlight(&reply.author)
+    } else {
+        reply.author.clone()
+    };
+    if reply.excerpt.is_empty() {
+        format!("(re {})", author)
+    } else {
+        format!("(re {}: \"{}\")", author, reply.excerpt)
+    }
+}
+
+/// Inserts a zero-width space after the first character, so IRC clients don't highlight a user with the same nick.
+fn break_highlight(nick: &str) -> String {
+    let mut chars = nick.chars();
+    match chars.next() {
+        Some(first) => format!("{}\u{200B}{}", first, chars.as_str()),
+        None => String::new(),
+    }
+}
+
+fn get_correct_webhook<'a>(
+    channel: &str,
+    routes: &Routes,
+    bridge_webhooks: &'a HashMap<ChannelId, Webhook>,
+) -> Option<&'a Webhook> {
+    routes
+        .discord_channel(channel)
+        .and_then(|id| bridge_webhooks.get(&id))
+}
+
+fn get_correct_channel(channel: &str, routes: &Routes) -> Option<ChannelId> {
+    routes.discord_channel(channel)
+}
+
+/// Builds the routing table from the `[[bridge]]` entries, plus the channels in the auto-discovery category unless it's disabled.
 async fn get_bridged_channels(
     http: &Arc<Http>,
     guild: &GuildId,
-) -> Result<HashMap<ChannelId, String>> {
-    let channels = guild.channels(http).await?;
+    config: &Config,
+) -> Result<Routes> {
+    let mut routes = Routes::default();
 
-    let irc_category = channels
-        .iter()
-        .filter(|x| x.1.kind == ChannelType::Category)
-        .filter(|x| x.1.name() == "irc")
-        .last();
+    for bridge in &config.bridge {
+        routes.insert(ChannelId(bridge.discord_channel), &bridge.irc_channel);
+    }
 
-    let mut vec = HashMap::new();
+    if !config.category.is_empty() {
+        let channels = guild.channels(http).await?;
 
-    if let Some(category) = irc_category {
-        vec = channels
+        let irc_category = channels
             .iter()
-            .filter(|x| x.1.parent_id.is_some())
-            .filter(|x| x.1.parent_id.unwrap().0 == category.0 .0)
-            .map(|x| (*x.0, x.1.name.clone()))
-            .collect();
+            .filter(|x| x.1.kind == ChannelType::Category)
+            .filter(|x| x.1.name() == config.category)
+            .last();
+
+        if let Some(category) = irc_category {
+            channels
+                .iter()
+                .filter(|x| x.1.parent_id == Some(*category.0))
+                .for_each(|x| routes.insert(*x.0, &x.1.name));
+        } else {
+            warn!("There is no \"{}\" category in the guild.", config.category);
+        }
     }
 
-    Ok(vec)
+    if routes.is_empty() {
+        warn!("No channels are bridged, add a [[bridge]] entry or a \"{}\" category.", config.category);
+    }
+
+    Ok(routes)
 }
 
 async fn get_or_create_webhooks(
     http: &Arc<Http>,
-    channels: &HashMap<ChannelId, String>,
-) -> Result<HashMap<String, Webhook>> {
-    let mut list: HashMap<String, Webhook> = HashMap::with_capacity(channels.len());
+    routes: &Routes,
+) -> Result<HashMap<ChannelId, Webhook>> {
+    let mut list: HashMap<ChannelId, Webhook> = HashMap::new();
 
-    'outer: for c in channels {
+    'outer: for c in routes.iter() {
         for hook in c.0.webhooks(http).await? {
             if let Some(name) = &hook.name {
                 if name.as_str() == "irc" {
                     info!("Found existing webhook for channel {} ({}).", &c.1, c.0 .0);
-                    list.insert(c.1.clone(), hook);
+                    list.insert(c.0, hook);
                     continue 'outer;
                 }
             }
         }
         let hook = c.0.create_webhook(http, "irc").await?;
         info!("Created webhook for channel {} ({}).", &c.1, c.0 .0);
-        list.insert(c.1.clone(), hook);
+        list.insert(c.0, hook);
     }
 
     Ok(list)
@@ -213,28 +1249,170 @@ async fn write(
     #[description = "Message"] msg: String
 ) -> Result<(), anyhow::Error> {
 
-    ctx.say(&msg).await?;
-    ctx.data().tx.send(CMessage { channel: ctx.channel_id(), message: msg })?;
+    // the bot posts this itself, so it mustn't ping anyone on the author's behalf
+    let sent = ctx.send(|m| m.content(&msg).allowed_mentions(|a| a.empty_parse())).await?.message().await?.id;
+    let author = ctx.author();
+    let nick = ctx.author_member().await.and_then(|m| m.nick.clone());
+    ctx.data().tx.send(DiscordEvent::Message(CMessage {
+        id: sent,
+        channel: ctx.channel_id(),
+        author_id: author.id,
+        author_nick: nick,
+        author_name: author.name.clone(),
+        message: resolve_mentions(&ctx.serenity_context().cache, &ctx.data().config, &[], &msg),
+        action: false,
+        attachments: Vec::new(),
+        reply: None,
+        edit: false,
+    }))?;
+
+    Ok(())
+}
+
+/// Sends an action to IRC, like `/me` does there.
+#[poise::command(slash_command)]
+async fn me(
+    ctx: Context<'_>,
+    #[description = "Action"] action: String
+) -> Result<(), anyhow::Error> {
+
+    let author = ctx.author();
+    let nick = ctx.author_member().await.and_then(|m| m.nick.clone());
+    let echo = format!(
+        "_\\* {} {}_",
+        formatting::escape(nick.as_deref().unwrap_or(&author.name)),
+        formatting::escape(&action)
+    );
+    let sent = ctx.send(|m| m.content(echo).allowed_mentions(|a| a.empty_parse())).await?.message().await?.id;
+    ctx.data().tx.send(DiscordEvent::Message(CMessage {
+        id: sent,
+        channel: ctx.channel_id(),
+        author_id: author.id,
+        author_nick: nick,
+        author_name: author.name.clone(),
+        message: resolve_mentions(&ctx.serenity_context().cache, &ctx.data().config, &[], &action),
+        action: true,
+        attachments: Vec::new(),
+        reply: None,
+        edit: false,
+    }))?;
 
     Ok(())
 }
 
+/// Puts mentions into words for IRC, from the users a message mentions and the guild cache.
+///
+/// Bridged channels are named after their IRC channel.
+///
+/// Names are escaped, so that markdown characters in them aren't taken for formatting.
+fn resolve_mentions(cache: &Cache, config: &Config, mentioned: &[serenity::User], text: &str) -> String {
+    let guild = GuildId(config.guild_id);
+    let name = |mention| match mention {
+        Mention::User(id) => {
+            let id = UserId(id);
+            mentioned
+                .iter()
+                .find(|u| u.id == id)
+                .map(|u| u.member.as_ref().and_then(|m| m.nick.clone()).unwrap_or_else(|| u.name.clone()))
+                .or_else(|| cache.member(guild, id).map(|m| m.display_name().into_owned()))
+                .or_else(|| cache.user(id).map(|u| u.name))
+        }
+        Mention::Role(id) => cache.role(guild, RoleId(id)).map(|r| r.name),
+        Mention::Channel(id) => match config.bridge_config(ChannelId(id)) {
+            Some(bridge) => Some(routes::irc_name(&bridge.irc_channel)),
+            None => cache.guild_channel(id).map(|c| format!("#{}", c.name)),
+        },
+    };
+    mentions::translate(text, |mention| name(mention).map(|n| formatting::escape(&n)))
+}
+
+/// Sums up the message a Discord message replies to.
+fn reply_to(cache: &Cache, config: &Config, referenced: &Message) -> Reply {
+    // webhook messages come from IRC, under the IRC user's nick
+    let author = match referenced.webhook_id {
+        Some(_) => referenced.author.name.clone(),
+        None => cache
+            .member(GuildId(config.guild_id), referenced.author.id)
+            .map(|m| m.display_name().into_owned())
+            .unwrap_or_else(|| referenced.author.name.clone()),
+    };
+    let text = resolve_mentions(cache, config, &referenced.mentions, &referenced.content);
+    let text = formatting::strip_irc_formatting(&formatting::discord_to_irc(&text));
+
+    Reply {
+        id: referenced.id,
+        author,
+        excerpt: formatting::excerpt(&text, REPLY_EXCERPT_CHARS),
+    }
+}
+
 struct Handler {
     options: poise::FrameworkOptions<Data, anyhow::Error>,
     data: Data,
     shard_manager: std::sync::Mutex<Option<std::sync::Arc<tokio::sync::Mutex<serenity::ShardManager>>>>,
     bot_id: RwLock<Option<UserId>>,
+    /// the IRC session lives as long as the process, not as long as a Discord gateway session
+    irc_started: AtomicBool,
 }
 
 struct Data {
     config: Config,
-    tx: broadcast::Sender<CMessage>,
+    tx: broadcast::Sender<DiscordEvent>,
+    webhook_ids: Arc<RwLock<HashSet<WebhookId>>>,
+    store: Arc<Store>,
+    /// asks the IRC session to reload its Discord-side state
+    refresh: Arc<Notify>,
+}
+
+/// How much of a replied-to message is quoted on IRC.
+const REPLY_EXCERPT_CHARS: usize = 40;
+
+/// What the Discord side hands over to the IRC connection.
+#[derive(Debug, Clone)]
+enum DiscordEvent {
+    Message(CMessage),
+    /// a message was edited, with its new text
+    Edit(CMessage),
+    /// messages were deleted, one by one or in bulk, maybe some that were sent to IRC
+    Delete { channel: ChannelId, messages: Vec<MessageId> },
+    /// a channel's topic was changed on Discord
+    Topic { channel: ChannelId, topic: String },
+}
+
+impl DiscordEvent {
+    fn channel(&self) -> ChannelId {
+        match self {
+            DiscordEvent::Message(msg) | DiscordEvent::Edit(msg) => msg.channel,
+            DiscordEvent::Delete { channel, .. } | DiscordEvent::Topic { channel, .. } => *channel,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 struct CMessage {
+    id: MessageId,
     channel: serenity::ChannelId,
-    message: String
+    author_id: UserId,
+    /// guild nickname, if the author has one
+    author_nick: Option<String>,
+    author_name: String,
+    message: String,
+    /// sent as a CTCP ACTION, like `/me`
+    action: bool,
+    /// attachments, stickers and embeds, summed up as IRC lines that follow the text
+    attachments: Vec<String>,
+    reply: Option<Reply>,
+    /// a correction of a message already sent to IRC
+    edit: bool,
+}
+
+/// The message a Discord message replies to.
+#[derive(Debug, Clone)]
+struct Reply {
+    id: MessageId,
+    author: String,
+    /// the start of its text, as plain IRC text
+    excerpt: String,
 }
 
 #[serenity::async_trait]
@@ -243,13 +1421,196 @@ impl serenity::EventHandler for Handler {
         let user_id = ctx.http.get_current_user().await.unwrap().id;
         let _ = self.bot_id.write().await.insert(user_id);
         info!("Discord connection ready");
-        info!("Starting IRC connection...");
-        tokio::spawn(irc(ctx.http.clone(), self.data.config.guild_id, self.data.tx.subscribe()));
         self.dispatch_poise_event(&ctx, &poise::Event::Ready { data_about_bot: ready }).await;
 
+        // Ready comes again after every gateway reconnect, only the first one starts the bridge
+        if self.irc_started.swap(true, Ordering::SeqCst) {
+            self.data.refresh.notify_one();
+            return;
+        }
+
+        info!("Starting IRC connection...");
+        tokio::spawn(irc(
+            ctx.http.clone(),
+            ctx.cache.clone(),
+            self.data.store.clone(),
+            self.data.config.clone(),
+            self.data.tx.subscribe(),
+            self.data.refresh.clone(),
+            self.data.webhook_ids.clone(),
+        ));
+
         poise::builtins::register_in_guild(ctx.http, &self.options.commands, GuildId(self.data.config.guild_id)).await.unwrap();
     }
 
+    async fn guild_create(&self, ctx: serenity::Context, guild: serenity::Guild, _is_new: bool) {
+        // large guilds only come with their online members, the rest have to be asked for
+        // so that IRC highlights of offline members still ping them
+        if guild.id == GuildId(self.data.config.guild_id) {
+            ctx.shard.chunk_guild(guild.id, None, serenity::ChunkGuildFilter::None, None);
+        }
+    }
+
+    async fn message(&self, ctx: serenity::Context, msg: Message) {
+        if msg.guild_id != Some(GuildId(self.data.config.guild_id)) {
+            return;
+        }
+
+        // ignore the bot itself and the bridge's own "irc" webhooks, or IRC messages would loop back
+        if Some(msg.author.id) == *self.bot_id.read().await {
+            return;
+        }
+        if let Some(id) = msg.webhook_id {
+            if self.data.webhook_ids.read().await.contains(&id) {
+                return;
+            }
+        }
+
+        let attachments = attachments::summarize(&msg);
+        if msg.content.is_empty() && attachments.is_empty() {
+            return;
+        }
+
+        // a message that is entirely in italics is how Discord users write actions
+        let (message, action) = match formatting::strip_whole_italic(&msg.content) {
+            Some(inner) => (inner, true),
+            None => (msg.content.as_str(), false),
+        };
+        let message = resolve_mentions(&ctx.cache, &self.data.config, &msg.mentions, message);
+        let reply = msg
+            .referenced_message
+            .as_deref()
+            .map(|r| reply_to(&ctx.cache, &self.data.config, r));
+
+        // no receiver just means the IRC connection isn't up yet
+        let _ = self.data.tx.send(DiscordEvent::Message(CMessage {
+            id: msg.id,
+            channel: msg.channel_id,
+            author_id: msg.author.id,
+            author_nick: msg.member.and_then(|m| m.nick),
+            author_name: msg.author.name,
+            message,
+            action,
+            attachments,
+            reply,
+            edit: false,
+        }));
+    }
+
+    async fn message_update(
+        &self,
+        ctx: serenity::Context,
+        _old: Option<Message>,
+        _new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        let config = &self.data.config;
+        let guild = GuildId(config.guild_id);
+        if event.guild_id != Some(guild) {
+            return;
+        }
+        let age = Timestamp::now().unix_timestamp() - event.id.created_at().unix_timestamp();
+        if age > config.edit_window_minutes as i64 * 60 {
+            return;
+        }
+
+        // link previews and other embeds arrive as updates without an edit timestamp
+        let (message, attachments) = match (event.content, event.edited_timestamp) {
+            (Some(content), Some(_)) => {
+                let text = formatting::strip_whole_italic(&content).unwrap_or(&content);
+                let mentioned = event.mentions.unwrap_or_default();
+                (resolve_mentions(&ctx.cache, config, &mentioned, text), Vec::new())
+            }
+            _ if config.relay_embed_edits => {
+                let embeds = event
+                    .embeds
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|e| attachments::embed(e.title.as_deref(), e.url.as_deref(), e.description.as_deref()))
+                    .collect();
+                (String::new(), embeds)
+            }
+            _ => return,
+        };
+        if message.is_empty() && attachments.is_empty() {
+            return;
+        }
+
+        // updates that only add embeds don't say who wrote the message, but the store knows if it was bridged
+        let (author_id, author_name) = match event.author {
+            Some(author) => (author.id, author.name),
+            None => {
+                let id = match self.data.store.sent_message(event.id) {
+                    Ok(Some(sent)) => sent.author,
+                    Ok(None) => return,
+                    Err(e) => {
+                        error!("failed to look up edited message: {}", e);
+                        return;
+                    }
+                };
+                (id, ctx.cache.user(id).map_or_else(|| id.to_string(), |u| u.name))
+            }
+        };
+
+        // whether the message was bridged at all is only known on the IRC side
+        let _ = self.data.tx.send(DiscordEvent::Edit(CMessage {
+            id: event.id,
+            channel: event.channel_id,
+            author_id,
+            author_nick: ctx.cache.member(guild, author_id).and_then(|m| m.nick),
+            author_name,
+            message,
+            action: false,
+            attachments,
+            reply: None,
+            edit: true,
+        }));
+    }
+
+    async fn message_delete(
+        &self,
+        _ctx: serenity::Context,
+        channel: ChannelId,
+        message: MessageId,
+        guild: Option<GuildId>,
+    ) {
+        if guild == Some(GuildId(self.data.config.guild_id)) {
+            let _ = self.data.tx.send(DiscordEvent::Delete { channel, messages: vec![message] });
+        }
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        _ctx: serenity::Context,
+        channel: ChannelId,
+        messages: Vec<MessageId>,
+        guild: Option<GuildId>,
+    ) {
+        if guild == Some(GuildId(self.data.config.guild_id)) {
+            let _ = self.data.tx.send(DiscordEvent::Delete { channel, messages });
+        }
+    }
+
+    async fn channel_update(&self, _ctx: serenity::Context, old: Option<Channel>, new: Channel) {
+        let Channel::Guild(channel) = new else {
+            return;
+        };
+        if channel.guild_id != GuildId(self.data.config.guild_id) {
+            return;
+        }
+        // renames and permission changes come through here too
+        if let Some(Channel::Guild(old)) = old {
+            if old.topic == channel.topic {
+                return;
+            }
+        }
+
+        let _ = self.data.tx.send(DiscordEvent::Topic {
+            channel: channel.id,
+            topic: channel.topic.unwrap_or_default(),
+        });
+    }
+
     async fn interaction_create(&self, ctx: serenity::Context, interaction: Interaction) {
         self.dispatch_poise_event(&ctx, &poise::Event::InteractionCreate { interaction }).await;
     }
@@ -267,10 +1628,45 @@ impl Handler {
     }
 }
 
-async fn irc(http: Arc<Http>, guild_id: u64, rx: broadcast::Receiver<CMessage>) {
-    match listen_irc(http, guild_id, rx).await {
-        Ok(_) => info!("listen_irc exited"),
-        Err(e) => error!("listen_irc error: {}", e)
+/// Keeps the IRC side of the bridge connected, reconnecting with backoff whenever the connection drops.
+async fn irc(
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+    store: Arc<Store>,
+    config: Config,
+    mut rx: broadcast::Receiver<DiscordEvent>,
+    refresh: Arc<Notify>,
+    webhook_ids: Arc<RwLock<HashSet<WebhookId>>>,
+) {
+    let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(300));
+    let mut state = None;
+
+    loop {
+        let state = match &mut state {
+            Some(state) => state,
+            None => match setup_discord(&http, &cache, &store, &config, &webhook_ids).await {
+                Ok(s) => state.insert(s),
+                Err(e) => {
+                    error!("failed to set up bridged Discord channels: {}", e);
+                    tokio::time::sleep(backoff.next_delay()).await;
+                    continue;
+                }
+            },
+        };
+
+        let mut connected = false;
+        match listen_irc(&http, &config, &mut rx, &refresh, &webhook_ids, state, &mut connected).await {
+            Ok(_) => error!("IRC connection closed"),
+            Err(e) => error!("IRC connection lost: {}", e),
+        }
+        if connected {
+            backoff.reset();
+        }
+        post_status_notices(&http, &config, state).await;
+
+        let delay = backoff.next_delay();
+        info!("Reconnecting to IRC in {:.1}s...", delay.as_secs_f32());
+        tokio::time::sleep(delay).await;
     }
 }
 
@@ -278,6 +1674,173 @@ async fn irc(http: Arc<Http>, guild_id: u64, rx: broadcast::Receiver<CMessage>)
 struct Config {
     token: String,
     guild_id: u64,
+    /// template for Discord messages sent to IRC, supports `{nick}`, `{name}`, `{id}` and `{text}`
+    #[serde(default = "default_message_template")]
+    message_template: String,
+    /// template for Discord actions, sent to IRC as `/me`, with the same placeholders
+    #[serde(default = "default_action_template")]
+    action_template: String,
+    /// insert a zero-width space in relayed nicks, so they don't highlight IRC users
+    #[serde(default)]
+    zero_width_nicks: bool,
+    /// maximum number of IRC lines a single Discord message can turn into
+    #[serde(default = "default_max_lines")]
+    max_lines: usize,
+    /// bridge every channel in the Discord category with this name to the IRC channel with the same name, empty to disable
+    #[serde(default = "default_category")]
+    category: String,
+    #[serde(default)]
+    bridge: Vec<BridgeConfig>,
+    /// posted in every bridged channel while the IRC connection is down, empty to disable
+    #[serde(default = "default_irc_down_notice")]
+    irc_down_notice: String,
+    /// nicks whose notices are forwarded from channels set to `notices = "services"`
+    #[serde(default = "default_service_nicks")]
+    service_nicks: Vec<String>,
+    /// IRC nicks and the Discord user IDs they belong to, so that highlighting them pings the Discord user
+    #[serde(default)]
+    linked_nicks: HashMap<String, u64>,
+    /// IDs of the roles IRC users can ping with `@rolename`, none by default
+    #[serde(default)]
+    mentionable_roles: Vec<u64>,
+    /// how many minutes after sending a message its edits still go to IRC
+    #[serde(default = "default_edit_window_minutes")]
+    edit_window_minutes: u64,
+    /// also relay updates that only add or change embeds, like link previews
+    #[serde(default)]
+    relay_embed_edits: bool,
+    /// notice sent to IRC when a Discord message sent there is deleted, with the same placeholders as
+    /// `message_template`, empty to disable; servers with `draft/message-redaction` get a REDACT instead,
+    /// along with the notice if the message went out as several IRC messages
+    #[serde(default = "default_delete_notice")]
+    delete_notice: String,
+    /// notice sent instead when several of them are deleted at once, `{count}` is how many, empty to disable
+    #[serde(default = "default_bulk_delete_notice")]
+    bulk_delete_notice: String,
+    /// SQLite database remembering which Discord message is which IRC message
+    #[serde(default = "default_database")]
+    database: String,
+    /// how many days messages are remembered in the database
+    #[serde(default = "default_retention_days")]
+    retention_days: u64,
+    /// serve avatars for IRC users, without this their messages get the webhook's own avatar
+    avatars: Option<AvatarConfig>,
+}
+
+impl Config {
+    fn bridge_config(&self, channel: ChannelId) -> Option<&BridgeConfig> {
+        self.bridge.iter().find(|b| b.discord_channel == channel.0)
+    }
+
+    /// Channels found through the category have no `[[bridge]]` entry and use the defaults.
+    fn notice_policy(&self, channel: ChannelId) -> NoticePolicy {
+        self.bridge_config(channel).map(|b| b.notices).unwrap_or_default()
+    }
+
+    /// How membership changes are mirrored, and for `active`, how recently someone must have spoken.
+    fn membership(&self, channel: ChannelId) -> (Membership, Duration) {
+        let (membership, minutes) = match self.bridge_config(channel) {
+            Some(b) => (b.membership, b.active_minutes),
+            None => (Membership::default(), default_active_minutes()),
+        };
+        (membership, Duration::from_secs(minutes * 60))
+    }
+}
+
+/// The `[avatars]` section, for the built-in avatar server.
+#[derive(Debug, Deserialize, Clone)]
+struct AvatarConfig {
+    /// address the HTTP server listens on
+    listen: SocketAddr,
+    /// URL the server is reachable at from Discord, like `https://bridge.example.com`
+    public_url: String,
+}
+
+/// A `[[bridge]]` entry, mapping a Discord channel to an IRC channel.
+#[derive(Debug, Deserialize, Clone)]
+struct BridgeConfig {
+    discord_channel: u64,
+    /// IRC channel name including its prefix, `#` is assumed if it has none
+    irc_channel: String,
+    #[serde(default)]
+    notices: NoticePolicy,
+    #[serde(default)]
+    membership: Membership,
+    /// how many minutes after speaking someone's membership changes are still shown with `membership = "active"`
+    #[serde(default = "default_active_minutes")]
+    active_minutes: u64,
+}
+
+/// Which IRC channel notices get forwarded to Discord.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum NoticePolicy {
+    #[default]
+    All,
+    None,
+    /// only notices from `service_nicks`
+    Services,
+}
+
+/// Which IRC joins, parts, quits, kicks and nick changes get mirrored to Discord.
+///
+/// Netsplits are summed up in a single line unless this is `none`.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Membership {
+    All,
+    None,
+    /// only for users who spoke in the channel within `active_minutes`
+    #[default]
+    Active,
+}
+
+fn default_message_template() -> String {
+    "<{nick}> {text}".to_string()
+}
+
+fn default_action_template() -> String {
+    "{nick} {text}".to_string()
+}
+
+fn default_category() -> String {
+    "irc".to_string()
+}
+
+fn default_max_lines() -> usize {
+    5
+}
+
+fn default_active_minutes() -> u64 {
+    30
+}
+
+fn default_delete_notice() -> String {
+    "[message from {nick} deleted]".to_string()
+}
+
+fn default_bulk_delete_notice() -> String {
+    "[{count} messages deleted]".to_string()
+}
+
+fn default_edit_window_minutes() -> u64 {
+    10
+}
+
+fn default_database() -> String {
+    "bridge.db".to_string()
+}
+
+fn default_retention_days() -> u64 {
+    30
+}
+
+fn default_irc_down_notice() -> String {
+    "IRC connection lost, reconnecting...".to_string()
+}
+
+fn default_service_nicks() -> Vec<String> {
+    vec!["ChanServ".to_string()]
 }
 
 async fn try_read_config(file: &str) -> Result<Config> {
@@ -289,3 +1852,303 @@ async fn try_read_config(file: &str) -> Result<Config> {
 
     Ok(a)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!("token = \"\"\nguild_id = 1\n{}", extra)).unwrap()
+    }
+
+    fn message(text: &str) -> CMessage {
+        CMessage {
+            id: MessageId(1),
+            channel: ChannelId(10),
+            author_id: UserId(100),
+            author_nick: Some("bob".to_string()),
+            author_name: "robert".to_string(),
+            message: text.to_string(),
+            action: false,
+            attachments: Vec::new(),
+            reply: None,
+            edit: false,
+        }
+    }
+
+    fn render(config: &Config, msg: &CMessage, max_bytes: usize) -> Vec<String> {
+        format_irc_lines(config, msg, max_bytes, false).iter().map(Line::full).collect()
+    }
+
+    #[test]
+    fn renders_default_templates() {
+        let config = config("");
+        assert_eq!(render(&config, &message("hello"), 400), vec!["<bob> hello"]);
+
+        let mut action = message("waves");
+        action.action = true;
+        assert_eq!(render(&config, &action, 400), vec!["bob waves"]);
+    }
+
+    #[test]
+    fn fills_every_placeholder() {
+        let config = config("message_template = \"[{name}/{id}] {nick}: {text}\"");
+        let mut msg = message("hello");
+        assert_eq!(render(&config, &msg, 400), vec!["[robert/100] bob: hello"]);
+
+        // without a guild nickname, the username stands in for it
+        msg.author_nick = None;
+        assert_eq!(render(&config, &msg, 400), vec!["[robert/100] robert: hello"]);
+    }
+
+    #[test]
+    fn placeholders_in_names_stay_literal() {
+        let config = config("message_template = \"<{nick}|{name}> {text}\"");
+        let mut msg = message(&"x".repeat(600));
+        msg.author_nick = Some("{text}{text}{text}".to_string());
+        msg.author_name = "{id}".to_string();
+
+        let hostmask_len = split::fallback_hostmask_len("bridge");
+        let budget = split::privmsg_budget(hostmask_len, "#test");
+        let lines = render(&config, &msg, budget);
+        assert!(lines[0].starts_with("<{text}{text}{text}|{id}> xxx"));
+        assert!(lines.iter().all(|l| l.len() <= budget));
+
+        let lines = format_irc_lines(&config, &msg, budget, true);
+        assert!(lines[0].full().starts_with("<{text}{text}{text}|{id}> xxx"));
+        assert!(lines.iter().all(|l| l.full().len() <= budget));
+        assert_eq!(fill_author(&config, "{nick} {{x} {", "{name}", "n", UserId(1)), "{name} {{x} {");
+    }
+
+    #[test]
+    fn breaks_highlights_in_nicks_and_names() {
+        let config = config("zero_width_nicks = true\nmessage_template = \"<{nick}|{name}> {text}\"");
+        assert_eq!(
+            render(&config, &message("bob is here"), 400),
+            vec!["<b\u{200B}ob|r\u{200B}obert> bob is here"]
+        );
+        assert_eq!(fill_author(&config, "{id}", "bob", "robert", UserId(100)), "100");
+    }
+
+    #[test]
+    fn lines_fit_in_a_privmsg() {
+        let config = config("message_template = \"<{nick}> [{name}] {text}\"");
+        let hostmask_len = split::fallback_hostmask_len("bridge");
+        let text = "the quick brown fox jumps over the lazy dög ".repeat(40);
+
+        let msg = message(&text);
+        let lines = render(&config, &msg, line_budget(hostmask_len, "#test", &msg));
+        assert!(lines.len() > 1);
+        for line in &lines {
+            assert!(line.starts_with("<bob> [robert] "));
+            assert!(line.len() <= split::privmsg_budget(hostmask_len, "#test"));
+        }
+
+        let mut action = message(&text);
+        action.action = true;
+        for line in render(&config, &action, line_budget(hostmask_len, "#test", &action)) {
+            assert!(ctcp::make_action(&line).len() <= split::privmsg_budget(hostmask_len, "#test"));
+        }
+    }
+
+    #[test]
+    fn truncates_to_max_lines() {
+        let config = config("max_lines = 2");
+        let lines = format_irc_lines(&config, &message("a\nb\nc\nd\ne"), 400, false);
+
+        let (sent, notice) = truncate_lines(&config, &lines);
+        assert_eq!(sent.iter().map(Line::full).collect::<Vec<_>>(), ["<bob> a", "<bob> b"]);
+        assert_eq!(notice.as_deref(), Some("[message truncated, 3 more lines]"));
+        assert_eq!(truncate_lines(&config, &lines[..2]), (&lines[..2], None));
+    }
+
+    #[test]
+    fn concatenated_lines_keep_one_prefix() {
+        let config = config("message_template = \"<{nick}> {text} (discord)\"");
+        let lines = format_irc_lines(&config, &message("aaaa bbbb cccc\ndddd"), 24, true);
+        assert_eq!(
+            lines.iter().map(|l| (l.text.as_str(), l.concat)).collect::<Vec<_>>(),
+            vec![("aaaa", false), (" bbbb", true), (" cccc", true), ("dddd", false)]
+        );
+        assert_eq!(
+            ircv3::batch_texts(&lines),
+            vec!["<bob> aaaa", " bbbb", " cccc (discord)", "<bob> dddd (discord)"]
+        );
+        assert!(lines.iter().all(|l| l.full().len() <= 24));
+
+        // truncating in the middle of a split line still ends it with the suffix
+        let config = Config { max_lines: 2, ..config };
+        let (sent, _) = truncate_lines(&config, &lines);
+        assert_eq!(ircv3::batch_texts(sent), vec!["<bob> aaaa", " bbbb (discord)"]);
+    }
+
+    const BRIDGES: &str = "
+        [[bridge]]
+        discord_channel = 1
+        irc_channel = \"#all\"
+
+        [[bridge]]
+        discord_channel = 2
+        irc_channel = \"#none\"
+        notices = \"none\"
+
+        [[bridge]]
+        discord_channel = 3
+        irc_channel = \"#services\"
+        notices = \"services\"
+    ";
+
+    #[test]
+    fn notices_follow_the_channel_policy() {
+        let config = config(&format!("service_nicks = [\"Chan[Serv]\"]\n{}", BRIDGES));
+        let rfc1459 = CaseMapping::Rfc1459;
+
+        assert_eq!(
+            notice_to_discord(&config, rfc1459, ChannelId(1), "alice", "\x02hi\x02 *there*").as_deref(),
+            Some("> **[notice]** **hi** \\*there\\*")
+        );
+        assert_eq!(notice_to_discord(&config, rfc1459, ChannelId(2), "Chan[Serv]", "hi"), None);
+
+        assert!(notice_to_discord(&config, rfc1459, ChannelId(3), "chan{serv}", "hi").is_some());
+        assert!(notice_to_discord(&config, CaseMapping::Ascii, ChannelId(3), "chan{serv}", "hi").is_none());
+        assert!(notice_to_discord(&config, rfc1459, ChannelId(3), "alice", "hi").is_none());
+
+        // channels from the category have no [[bridge]] entry and take everything
+        assert!(notice_to_discord(&config, rfc1459, ChannelId(4), "alice", "hi").is_some());
+    }
+
+    const MEMBERSHIP: &str = "
+        [[bridge]]
+        discord_channel = 1
+        irc_channel = \"#all\"
+        membership = \"all\"
+
+        [[bridge]]
+        discord_channel = 2
+        irc_channel = \"#active\"
+
+        [[bridge]]
+        discord_channel = 3
+        irc_channel = \"#none\"
+        membership = \"none\"
+    ";
+
+    fn membership_setup() -> (Config, Routes, IrcState) {
+        let config = config(MEMBERSHIP);
+        let mut routes = Routes::default();
+        for bridge in &config.bridge {
+            routes.insert(ChannelId(bridge.discord_channel), &bridge.irc_channel);
+        }
+        (config, routes, IrcState::default())
+    }
+
+    fn feed(config: &Config, routes: &Routes, irc: &mut IrcState, line: &str) -> Vec<(ChannelId, String)> {
+        let message: irc::proto::Message = line.parse().unwrap();
+        let mut lines = membership_lines(config, routes, irc, "bridge", &message);
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn mirrors_joins_parts_and_kicks() {
+        let (config, routes, mut irc) = membership_setup();
+        let all = ChannelId(1);
+
+        assert!(feed(&config, &routes, &mut irc, ":bridge!b@host JOIN #all").is_empty());
+        assert!(irc.presence.is_in("#all"));
+        assert_eq!(
+            feed(&config, &routes, &mut irc, ":al_ice!a@host JOIN #All"),
+            vec![(all, "_→ al\\_ice joined_".to_string())]
+        );
+        assert_eq!(
+            feed(&config, &routes, &mut irc, ":al_ice!a@host PART #all :see you"),
+            vec![(all, "_← al\\_ice left (see you)_".to_string())]
+        );
+        assert_eq!(
+            feed(&config, &routes, &mut irc, ":op!o@host KICK #all bob :spam"),
+            vec![(all, "_← bob was kicked by op (spam)_".to_string())]
+        );
+        assert!(feed(&config, &routes, &mut irc, ":alice!a@host JOIN #none").is_empty());
+
+        assert!(feed(&config, &routes, &mut irc, ":op!o@host KICK #all bridge :bye").is_empty());
+        assert!(!irc.presence.is_in("#all"));
+    }
+
+    #[test]
+    fn active_channels_only_show_recent_speakers() {
+        let (config, routes, mut irc) = membership_setup();
+        let (all, active) = (ChannelId(1), ChannelId(2));
+        feed(&config, &routes, &mut irc, ":bridge!b@host JOIN #active");
+        feed(&config, &routes, &mut irc, ":irc.test 353 bridge = #active :@alice +carol bridge");
+        feed(&config, &routes, &mut irc, ":bridge!b@host JOIN #all");
+        feed(&config, &routes, &mut irc, ":irc.test 353 bridge = #all :alice bridge");
+
+        assert_eq!(
+            feed(&config, &routes, &mut irc, ":alice!a@host NICK alicia"),
+            vec![(all, "_alice is now known as alicia_".to_string())]
+        );
+
+        irc.presence.spoke("#active", "alicia");
+        assert_eq!(
+            feed(&config, &routes, &mut irc, ":alicia!a@host QUIT :Quit: gone"),
+            vec![
+                (all, "_← alicia quit (Quit: gone)_".to_string()),
+                (active, "_← alicia quit (Quit: gone)_".to_string()),
+            ]
+        );
+        assert!(feed(&config, &routes, &mut irc, ":carol!c@host QUIT :bye").is_empty());
+        assert!(feed(&config, &routes, &mut irc, ":dave!d@host JOIN #active").is_empty());
+    }
+
+    #[test]
+    fn resolved_names_are_not_markdown() {
+        let cache = Cache::new();
+        let mut user = serenity::User::default();
+        user.name = "__init__".to_string();
+        let text = resolve_mentions(&cache, &config(""), &[user], "<@210> says *hi*");
+        assert_eq!(formatting::discord_to_irc(&text), "@__init__ says \x1Dhi\x1D");
+    }
+
+    #[test]
+    fn linked_nicks_follow_the_casemapping() {
+        let cache = Cache::new();
+        let config = config("[linked_nicks]\n\"Al[ice]\" = 5");
+
+        assert_eq!(find_member(&cache, &config, CaseMapping::Rfc1459, "AL[ICE]"), Some(UserId(5)));
+        assert_eq!(find_member(&cache, &config, CaseMapping::Rfc1459, "al{ice}"), Some(UserId(5)));
+        assert_eq!(find_member(&cache, &config, CaseMapping::Ascii, "al{ice}"), None);
+    }
+
+    #[test]
+    fn category_bridged_unless_disabled() {
+        assert_eq!(config("").category, "irc");
+        assert_eq!(config("category = \"\"").category, "");
+    }
+
+    #[test]
+    fn one_notice_for_many_deletions() {
+        let cache = Cache::new();
+        let defaults = config("");
+
+        assert_eq!(delete_notice(&defaults, &cache, &[]), None);
+        assert_eq!(
+            delete_notice(&defaults, &cache, &[UserId(100)]).as_deref(),
+            Some("[message from 100 deleted]")
+        );
+        assert_eq!(
+            delete_notice(&defaults, &cache, &[UserId(100), UserId(100), UserId(200)]).as_deref(),
+            Some("[3 messages deleted]")
+        );
+
+        let disabled = config("delete_notice = \"\"\nbulk_delete_notice = \"\"");
+        assert_eq!(delete_notice(&disabled, &cache, &[UserId(100)]), None);
+        assert_eq!(delete_notice(&disabled, &cache, &[UserId(100), UserId(200)]), None);
+    }
+
+    #[test]
+    fn break_highlight_inserts_after_first_char() {
+        assert_eq!(break_highlight("bob"), "b\u{200B}ob");
+        assert_eq!(break_highlight("ébé"), "é\u{200B}bé");
+        assert_eq!(break_highlight(""), "");
+    }
+}
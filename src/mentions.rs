@@ -0,0 +1,157 @@
+//! Discord's mention, emoji and timestamp markup, turned into text that reads well on IRC.
+
+use poise::serenity_prelude::Timestamp;
+
+/// Something Discord renders from `<...>` markup, and that needs a guild lookup to put into words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mention {
+    User(u64),
+    Role(u64),
+    Channel(u64),
+}
+
+/// Replaces mentions with the names `resolve` finds for them, custom emoji with `:name:`, and timestamps with UTC times.
+///
+/// Markup inside code spans and blocks is left alone, like Discord does.
+pub fn translate(text: &str, resolve: impl Fn(Mention) -> Option<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(i) = rest.find(['<', '`']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if rest.starts_with('`') {
+            let ticks = rest.len() - rest.trim_start_matches('`').len();
+            let fence = &rest[..ticks];
+            let end = rest[ticks..]
+                .find(fence)
+                .map_or(ticks, |j| ticks + j + ticks);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+
+        match rest
+            .find('>')
+            .and_then(|end| Some((markup(&rest[1..end], &resolve)?, end)))
+        {
+            Some((replacement, end)) => {
+                out.push_str(&replacement);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Renders the inside of a `<...>`, or returns `None` if it isn't markup Discord knows.
+fn markup(inner: &str, resolve: impl Fn(Mention) -> Option<String>) -> Option<String> {
+    if let Some(id) = inner.strip_prefix("@&") {
+        let name = resolve(Mention::Role(id.parse().ok()?));
+        return Some(format!("@{}", name.as_deref().unwrap_or("deleted-role")));
+    }
+    if let Some(id) = inner.strip_prefix('@') {
+        // `<@!id>` is the old form for members with a nickname
+        let id = id.strip_prefix('!').unwrap_or(id);
+        let name = resolve(Mention::User(id.parse().ok()?));
+        return Some(format!("@{}", name.as_deref().unwrap_or("unknown-user")));
+    }
+    if let Some(id) = inner.strip_prefix('#') {
+        let name = resolve(Mention::Channel(id.parse().ok()?));
+        return Some(name.unwrap_or_else(|| "#deleted-channel".to_string()));
+    }
+    if let Some(emoji) = inner.strip_prefix("a:").or_else(|| inner.strip_prefix(':')) {
+        let (name, id) = emoji.split_once(':')?;
+        id.parse::<u64>().ok()?;
+        return Some(format!(":{}:", name));
+    }
+    if let Some(timestamp) = inner.strip_prefix("t:") {
+        let (secs, style) = timestamp.split_once(':').unwrap_or((timestamp, "f"));
+        return timestamp_text(secs.parse().ok()?, style);
+    }
+
+    None
+}
+
+/// Formats a `<t:...>` timestamp in UTC, keeping to the parts its style shows.
+fn timestamp_text(secs: i64, style: &str) -> Option<String> {
+    let time = Timestamp::from_unix_timestamp(secs).ok()?;
+    let format = match style {
+        "t" => "%H:%M UTC",
+        "T" => "%H:%M:%S UTC",
+        "d" | "D" => "%Y-%m-%d",
+        // full dates and relative times alike
+        _ => "%Y-%m-%d %H:%M UTC",
+    };
+    Some(time.format(format).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(mention: Mention) -> Option<String> {
+        match mention {
+            Mention::User(1) => Some("Alice".to_string()),
+            Mention::Role(2) => Some("Moderators".to_string()),
+            Mention::Channel(3) => Some("#general".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn resolves_mentions() {
+        assert_eq!(
+            translate("<@1> <@!1> <@&2> <#3>", names),
+            "@Alice @Alice @Moderators #general"
+        );
+    }
+
+    #[test]
+    fn unknown_ids_get_placeholders() {
+        assert_eq!(
+            translate("<@9> <@&9> <#9>", names),
+            "@unknown-user @deleted-role #deleted-channel"
+        );
+    }
+
+    #[test]
+    fn shortens_custom_emoji() {
+        assert_eq!(
+            translate("hi <:wave:123> <a:party:456>", names),
+            "hi :wave: :party:"
+        );
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(translate("<t:1690000000>", names), "2023-07-22 04:26 UTC");
+        assert_eq!(translate("<t:1690000000:R>", names), "2023-07-22 04:26 UTC");
+        assert_eq!(translate("<t:1690000000:T>", names), "04:26:40 UTC");
+        assert_eq!(translate("<t:1690000000:D>", names), "2023-07-22");
+    }
+
+    #[test]
+    fn leaves_other_brackets() {
+        assert_eq!(translate("a < b > c <@x>", names), "a < b > c <@x>");
+        assert_eq!(
+            translate("<https://example.com>", names),
+            "<https://example.com>"
+        );
+    }
+
+    #[test]
+    fn skips_code() {
+        assert_eq!(
+            translate("`<@1>` <@1> ```\n<#3>\n```", names),
+            "`<@1>` @Alice ```\n<#3>\n```"
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Netsplits and netjoins, coalesced into one summary line per channel.
+//!
+//! When two servers lose each other, everyone behind the other one quits at once with both server names as the
+//! reason, and joins back when the link is restored.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
+
+use poise::serenity_prelude::ChannelId;
+
+use crate::formatting;
+use crate::isupport::CaseMapping;
+
+/// How long quits and joins of a split are collected before their summary goes out.
+pub const WINDOW: Duration = Duration::from_secs(5);
+/// How long nicks lost in a split are remembered, to recognize them joining back.
+const MEMORY: Duration = Duration::from_secs(30 * 60);
+
+/// Returns the two servers of a netsplit QUIT reason, like `hub.a leaf.b`.
+pub fn split_servers(reason: &str) -> Option<(&str, &str)> {
+    let (a, b) = reason.split_once(' ')?;
+    let is_server = |name: &str| {
+        name.contains('.')
+            && !name.starts_with('.')
+            && !name.ends_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '*'))
+    };
+    (is_server(a) && is_server(b) && a != b).then_some((a, b))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Split,
+    Join,
+}
+
+#[derive(Debug)]
+struct Batch {
+    kind: Kind,
+    servers: (String, String),
+    channel: ChannelId,
+    nicks: usize,
+    started: Instant,
+}
+
+#[derive(Debug)]
+struct Lost {
+    servers: (String, String),
+    /// channels the nick hasn't come back to yet
+    channels: HashSet<ChannelId>,
+    at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Netsplits {
+    batches: Vec<Batch>,
+    /// nicks that quit in a split, folded through `casemapping`
+    lost: HashMap<String, Lost>,
+    casemapping: CaseMapping,
+}
+
+impl Netsplits {
+    pub fn set_casemapping(&mut self, casemapping: CaseMapping) {
+        self.casemapping = casemapping;
+    }
+
+    /// Counts a nick lost in a split, in the bridged channels it was in.
+    pub fn quit(&mut self, servers: (&str, &str), nick: &str, channels: &[ChannelId]) {
+        if channels.is_empty() {
+            return;
+        }
+        let servers = (servers.0.to_string(), servers.1.to_string());
+        for &channel in channels {
+            self.count(Kind::Split, &servers, channel);
+        }
+        self.lost.insert(
+            self.casemapping.normalize(nick),
+            Lost {
+                servers,
+                channels: channels.iter().copied().collect(),
+                at: Instant::now(),
+            },
+        );
+    }
+
+    /// Counts a join if it is someone coming back from a split, returning whether it was.
+    pub fn join(&mut self, nick: &str, channel: ChannelId) -> bool {
+        let nick = self.casemapping.normalize(nick);
+        let Some(lost) = self.lost.get_mut(&nick) else {
+            return false;
+        };
+        if lost.at.elapsed() > MEMORY || !lost.channels.remove(&channel) {
+            return false;
+        }
+        let servers = lost.servers.clone();
+        if lost.channels.is_empty() {
+            self.lost.remove(&nick);
+        }
+        self.count(Kind::Join, &servers, channel);
+        true
+    }
+
+    /// Takes the summaries of the batches whose window is over.
+    pub fn flush(&mut self) -> Vec<(ChannelId, String)> {
+        self.lost.retain(|_, lost| lost.at.elapsed() <= MEMORY);
+
+        let (due, pending) = self
+            .batches
+            .drain(..)
+            .partition(|batch| batch.started.elapsed() >= WINDOW);
+        self.batches = pending;
+
+        due.into_iter()
+            .map(|batch| {
+                let (what, verb) = match batch.kind {
+                    Kind::Split => ("Netsplit", "quit"),
+                    Kind::Join => ("Netjoin", "returned"),
+                };
+                let text = format!(
+                    "{} {} ↔ {}: {} {} {}",
+                    what,
+                    formatting::irc_to_discord(&batch.servers.0),
+                    formatting::irc_to_discord(&batch.servers.1),
+                    batch.nicks,
+                    if batch.nicks == 1 { "user" } else { "users" },
+                    verb
+                );
+                (batch.channel, text)
+            })
+            .collect()
+    }
+
+    fn count(&mut self, kind: Kind, servers: &(String, String), channel: ChannelId) {
+        let batch = self
+            .batches
+            .iter_mut()
+            .find(|b| b.kind == kind && b.channel == channel && b.servers == *servers);
+        match batch {
+            Some(batch) => batch.nicks += 1,
+            None => self.batches.push(Batch {
+                kind,
+                servers: servers.clone(),
+                channel,
+                nicks: 1,
+                started: Instant::now(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes every batch due, instead of waiting out the window.
+    fn expire(netsplits: &mut Netsplits) {
+        for batch in &mut netsplits.batches {
+            batch.started -= WINDOW;
+        }
+    }
+
+    #[test]
+    fn recognizes_split_reasons() {
+        assert_eq!(
+            split_servers("hub.example.net leaf.example.net"),
+            Some(("hub.example.net", "leaf.example.net"))
+        );
+        assert_eq!(split_servers("*.net *.split"), Some(("*.net", "*.split")));
+        assert_eq!(split_servers("Quit: bye"), None);
+        assert_eq!(split_servers("see you.later"), None);
+        assert_eq!(split_servers("irc.a irc.a"), None);
+    }
+
+    #[test]
+    fn coalesces_quits_per_channel() {
+        let mut netsplits = Netsplits::default();
+        let (a, b) = (ChannelId(1), ChannelId(2));
+        netsplits.quit(("hub.a", "hub.b"), "alice", &[a, b]);
+        netsplits.quit(("hub.a", "hub.b"), "bob", &[a]);
+        assert!(netsplits.flush().is_empty());
+
+        expire(&mut netsplits);
+        let mut lines = netsplits.flush();
+        lines.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            lines,
+            vec![
+                (a, "Netsplit hub.a ↔ hub.b: 2 users quit".to_string()),
+                (b, "Netsplit hub.a ↔ hub.b: 1 user quit".to_string()),
+            ]
+        );
+        assert!(netsplits.flush().is_empty());
+    }
+
+    #[test]
+    fn counts_returning_nicks() {
+        let mut netsplits = Netsplits::default();
+        let a = ChannelId(1);
+        netsplits.quit(("hub.a", "hub.b"), "Alice", &[a]);
+        expire(&mut netsplits);
+        netsplits.flush();
+
+        assert!(netsplits.join("alice", a));
+        assert!(!netsplits.join("alice", a));
+        assert!(!netsplits.join("carol", a));
+
+        expire(&mut netsplits);
+        assert_eq!(
+            netsplits.flush(),
+            vec![(a, "Netjoin hub.a ↔ hub.b: 1 user returned".to_string())]
+        );
+    }
+}
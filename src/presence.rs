@@ -0,0 +1,210 @@
+//! Who is in which IRC channel, and who spoke recently.
+//!
+//! QUIT and NICK don't say which channels they affect, so the bridge keeps its own member lists to know where to mirror them.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::isupport::CaseMapping;
+
+/// Channel membership prefixes that can appear in front of nicks in `RPL_NAMREPLY`.
+const MEMBER_PREFIXES: &[char] = &['~', '&', '@', '%', '+'];
+
+/// Past this many remembered speakers, the ones silent for longer than `FORGET_AFTER` are dropped.
+const MAX_SPEAKERS: usize = 4096;
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Default)]
+pub struct Presence {
+    /// nicks in every channel we're in, both folded through `casemapping`
+    members: HashMap<String, HashSet<String>>,
+    /// when each nick last spoke in each channel
+    last_spoke: HashMap<(String, String), Instant>,
+    casemapping: CaseMapping,
+}
+
+impl Presence {
+    pub fn set_casemapping(&mut self, casemapping: CaseMapping) {
+        self.casemapping = casemapping;
+    }
+
+    /// Adds the nicks from an `RPL_NAMREPLY` line.
+    pub fn names(&mut self, channel: &str, names: &str) {
+        let members = self
+            .members
+            .entry(self.casemapping.normalize(channel))
+            .or_default();
+        for name in names.split_whitespace() {
+            members.insert(
+                self.casemapping
+                    .normalize(name.trim_start_matches(MEMBER_PREFIXES)),
+            );
+        }
+    }
+
+    pub fn join(&mut self, channel: &str, nick: &str) {
+        self.members
+            .entry(self.casemapping.normalize(channel))
+            .or_default()
+            .insert(self.casemapping.normalize(nick));
+    }
+
+    /// Removes a nick from a channel, after a PART or a KICK.
+    pub fn part(&mut self, channel: &str, nick: &str) {
+        let channel = self.casemapping.normalize(channel);
+        let nick = self.casemapping.normalize(nick);
+        if let Some(members) = self.members.get_mut(&channel) {
+            members.remove(&nick);
+        }
+    }
+
+    /// Starts over with an empty member list for a channel we joined.
+    pub fn enter(&mut self, channel: &str) {
+        self.leave(channel);
+        self.members
+            .insert(self.casemapping.normalize(channel), HashSet::new());
+    }
+
+    /// Whether we are in a channel, as far as JOIN, PART and KICK tell.
+    pub fn is_in(&self, channel: &str) -> bool {
+        self.members
+            .contains_key(&self.casemapping.normalize(channel))
+    }
+
+    /// Forgets everything about a channel we left.
+    pub fn leave(&mut self, channel: &str) {
+        let channel = self.casemapping.normalize(channel);
+        self.members.remove(&channel);
+        self.last_spoke.retain(|(c, _), _| *c != channel);
+    }
+
+    /// Removes a nick from every channel, returning the channels it was in.
+    pub fn quit(&mut self, nick: &str) -> Vec<String> {
+        let nick = self.casemapping.normalize(nick);
+        let channels = self.channels_of(&nick);
+        for members in self.members.values_mut() {
+            members.remove(&nick);
+        }
+        channels
+    }
+
+    /// Renames a nick everywhere, returning the channels it is in.
+    pub fn nick(&mut self, old: &str, new: &str) -> Vec<String> {
+        let old = self.casemapping.normalize(old);
+        let new = self.casemapping.normalize(new);
+        let channels = self.channels_of(&old);
+        for members in self.members.values_mut() {
+            if members.remove(&old) {
+                members.insert(new.clone());
+            }
+        }
+        let moved: Vec<_> = self
+            .last_spoke
+            .iter()
+            .filter(|((_, n), _)| *n == old)
+            .map(|((c, _), t)| (c.clone(), *t))
+            .collect();
+        for (channel, time) in moved {
+            self.last_spoke.remove(&(channel.clone(), old.clone()));
+            self.last_spoke.insert((channel, new.clone()), time);
+        }
+        channels
+    }
+
+    /// Records that a nick spoke in a channel.
+    ///
+    /// This survives parts and quits, so that someone who was just talking is still shown when they rejoin.
+    pub fn spoke(&mut self, channel: &str, nick: &str) {
+        if self.last_spoke.len() >= MAX_SPEAKERS {
+            self.last_spoke.retain(|_, t| t.elapsed() < FORGET_AFTER);
+        }
+        self.last_spoke.insert(
+            (
+                self.casemapping.normalize(channel),
+                self.casemapping.normalize(nick),
+            ),
+            Instant::now(),
+        );
+    }
+
+    pub fn spoke_within(&self, channel: &str, nick: &str, window: Duration) -> bool {
+        let key = (
+            self.casemapping.normalize(channel),
+            self.casemapping.normalize(nick),
+        );
+        self.last_spoke
+            .get(&key)
+            .is_some_and(|t| t.elapsed() <= window)
+    }
+
+    fn channels_of(&self, nick: &str) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(_, members)| members.contains(nick))
+            .map(|(channel, _)| channel.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quit_reports_channels() {
+        let mut presence = Presence::default();
+        presence.names("#a", "@Alice +bob carol");
+        presence.join("#B", "alice");
+
+        let mut channels = presence.quit("ALICE");
+        channels.sort();
+        assert_eq!(channels, vec!["#a", "#b"]);
+        assert!(presence.quit("alice").is_empty());
+    }
+
+    #[test]
+    fn nick_keeps_activity() {
+        let mut presence = Presence::default();
+        presence.join("#a", "alice");
+        presence.spoke("#a", "alice");
+
+        assert_eq!(presence.nick("alice", "alicia"), vec!["#a"]);
+        assert!(presence.spoke_within("#a", "alicia", Duration::from_secs(60)));
+        assert!(!presence.spoke_within("#a", "alice", Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn activity_survives_part() {
+        let mut presence = Presence::default();
+        presence.join("#a", "bob");
+        presence.spoke("#a", "bob");
+        presence.part("#a", "bob");
+
+        assert!(presence.spoke_within("#a", "bob", Duration::from_secs(60)));
+        assert!(presence.quit("bob").is_empty());
+    }
+
+    #[test]
+    fn knows_channels_we_are_in() {
+        let mut presence = Presence::default();
+        assert!(!presence.is_in("#a"));
+
+        presence.enter("#A");
+        assert!(presence.is_in("#a"));
+        presence.leave("#a");
+        assert!(!presence.is_in("#A"));
+    }
+
+    #[test]
+    fn leave_forgets_channel() {
+        let mut presence = Presence::default();
+        presence.join("#a", "bob");
+        presence.spoke("#a", "bob");
+        presence.leave("#a");
+
+        assert!(!presence.spoke_within("#a", "bob", Duration::from_secs(60)));
+        assert!(presence.quit("bob").is_empty());
+    }
+}
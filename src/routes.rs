@@ -0,0 +1,163 @@
+//! Routing between bridged Discord channels and IRC channels.
+
+use std::collections::HashMap;
+
+use poise::serenity_prelude::ChannelId;
+use tracing::warn;
+
+use crate::isupport::CaseMapping;
+
+/// Characters an IRC channel name can start with.
+const CHANNEL_PREFIXES: &[char] = &['#', '&', '+', '!'];
+
+/// Adds the `#` prefix to an IRC channel name that has none.
+pub fn irc_name(name: &str) -> String {
+    if name.starts_with(CHANNEL_PREFIXES) {
+        name.to_string()
+    } else {
+        format!("#{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    /// full IRC channel name for every bridged Discord channel
+    to_irc: HashMap<ChannelId, String>,
+    /// IRC channel names folded through `casemapping`, for lookups from IRC
+    from_irc: HashMap<String, ChannelId>,
+    casemapping: CaseMapping,
+}
+
+impl Routes {
+    /// Adds a route, unless either channel is already bridged somewhere else.
+    pub fn insert(&mut self, discord: ChannelId, irc: &str) {
+        let irc = irc_name(irc);
+        if self.to_irc.contains_key(&discord) {
+            return;
+        }
+        let key = self.casemapping.normalize(&irc);
+        if let Some(other) = self.from_irc.get(&key) {
+            warn!(
+                "{} is already bridged to Discord channel {}, not bridging it to {} too",
+                irc, other.0, discord.0
+            );
+            return;
+        }
+        self.from_irc.insert(key, discord);
+        self.to_irc.insert(discord, irc);
+    }
+
+    /// Switches to the casemapping the server announced, so lookups match the way it compares names.
+    pub fn set_casemapping(&mut self, casemapping: CaseMapping) {
+        if casemapping == self.casemapping {
+            return;
+        }
+        self.casemapping = casemapping;
+        self.from_irc.clear();
+        // names that were different before can be the same channel now
+        let mut collisions = Vec::new();
+        for (id, name) in &self.to_irc {
+            let key = casemapping.normalize(name);
+            match self.from_irc.get(&key) {
+                // keep the older Discord channel, so hash order doesn't decide
+                Some(other) if other.0 < id.0 => collisions.push(*id),
+                Some(other) => {
+                    collisions.push(*other);
+                    self.from_irc.insert(key, *id);
+                }
+                None => {
+                    self.from_irc.insert(key, *id);
+                }
+            }
+        }
+        for id in collisions {
+            if let Some(name) = self.to_irc.remove(&id) {
+                warn!(
+                    "{} is the same channel as another bridged one under {:?}, no longer bridging it to Discord channel {}",
+                    name, casemapping, id.0
+                );
+            }
+        }
+    }
+
+    pub fn irc_channel(&self, discord: ChannelId) -> Option<&str> {
+        self.to_irc.get(&discord).map(String::as_str)
+    }
+
+    pub fn discord_channel(&self, irc: &str) -> Option<ChannelId> {
+        self.from_irc.get(&self.casemapping.normalize(irc)).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to_irc.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ChannelId, &str)> {
+        self.to_irc.iter().map(|(id, name)| (*id, name.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn irc_name_adds_missing_prefix() {
+        assert_eq!(irc_name("rust"), "#rust");
+        assert_eq!(irc_name("#rust"), "#rust");
+        assert_eq!(irc_name("&local"), "&local");
+        assert_eq!(irc_name("+modeless"), "+modeless");
+    }
+
+    #[test]
+    fn lookups_ignore_case() {
+        let mut routes = Routes::default();
+        routes.insert(ChannelId(1), "Rust");
+
+        assert_eq!(routes.irc_channel(ChannelId(1)), Some("#Rust"));
+        assert_eq!(routes.discord_channel("#rust"), Some(ChannelId(1)));
+        assert_eq!(routes.discord_channel("#RUST"), Some(ChannelId(1)));
+        assert_eq!(routes.discord_channel("rust"), None);
+    }
+
+    #[test]
+    fn first_route_wins() {
+        let mut routes = Routes::default();
+        routes.insert(ChannelId(1), "#rust");
+        routes.insert(ChannelId(2), "#RUST");
+        routes.insert(ChannelId(1), "#other");
+
+        assert_eq!(routes.discord_channel("#rust"), Some(ChannelId(1)));
+        assert_eq!(routes.irc_channel(ChannelId(1)), Some("#rust"));
+        assert_eq!(routes.irc_channel(ChannelId(2)), None);
+        assert_eq!(routes.discord_channel("#other"), None);
+    }
+
+    #[test]
+    fn follows_the_server_casemapping() {
+        let mut routes = Routes::default();
+        routes.insert(ChannelId(1), "#Foo[]");
+        // rfc1459 until the server says otherwise
+        assert_eq!(routes.discord_channel("#foo{}"), Some(ChannelId(1)));
+
+        routes.set_casemapping(CaseMapping::Ascii);
+        assert_eq!(routes.discord_channel("#Foo[]"), Some(ChannelId(1)));
+        assert_eq!(routes.discord_channel("#FOO[]"), Some(ChannelId(1)));
+        assert_eq!(routes.discord_channel("#foo{}"), None);
+        assert_eq!(routes.irc_channel(ChannelId(1)), Some("#Foo[]"));
+    }
+
+    #[test]
+    fn casemapping_can_merge_routes() {
+        let mut routes = Routes::default();
+        routes.set_casemapping(CaseMapping::Ascii);
+        routes.insert(ChannelId(2), "#a[b]");
+        routes.insert(ChannelId(1), "#a{b}");
+        assert_eq!(routes.iter().count(), 2);
+
+        routes.set_casemapping(CaseMapping::Rfc1459);
+        assert_eq!(routes.discord_channel("#a[b]"), Some(ChannelId(1)));
+        assert_eq!(routes.irc_channel(ChannelId(2)), None);
+        assert_eq!(routes.iter().count(), 1);
+    }
+}
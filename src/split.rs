@@ -0,0 +1,128 @@
+//! Splitting of outgoing text into lines that fit in a single IRC message.
+
+/// Maximum length of an IRC line, including the trailing CRLF.
+pub const MAX_LINE_BYTES: usize = 512;
+
+/// Worst case length of the `nick!user@host` the server prepends when relaying our messages,
+/// used until the real one is known. USERLEN is usually 10 and hostnames are at most 63 bytes.
+pub fn fallback_hostmask_len(nick: &str) -> usize {
+    nick.len() + 1 + 10 + 1 + 63
+}
+
+/// Bytes left for the text of a `PRIVMSG <target> :<text>` once the server has added our hostmask.
+pub fn privmsg_budget(hostmask_len: usize, target: &str) -> usize {
+    // ":<hostmask> PRIVMSG <target> :<text>\r\n"
+    let overhead = 1 + hostmask_len + 1 + "PRIVMSG ".len() + target.len() + " :".len() + 2;
+    MAX_LINE_BYTES.saturating_sub(overhead)
+}
+
+/// Splits a line into chunks of at most `max_bytes`, breaking at spaces when possible.
+///
+/// Chunks never end in the middle of a UTF-8 code point, and a single character is always
+/// emitted whole even if it doesn't fit on its own.
+pub fn split_line(text: &str, max_bytes: usize) -> Vec<String> {
+    split(text, max_bytes, false)
+}
+
+/// Like `split_line`, but the space at a break starts the next chunk, so the chunks join back into
+/// the original text. Meant for `draft/multiline-concat`, where clients join them without a separator.
+pub fn split_line_concat(text: &str, max_bytes: usize) -> Vec<String> {
+    split(text, max_bytes, true)
+}
+
+fn split(text: &str, max_bytes: usize, keep_spaces: bool) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+
+    while rest.len() > max_bytes {
+        let mut end = max_bytes;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        // a space right at the limit is a perfectly good place to break
+        let window = if rest.as_bytes()[end] == b' ' {
+            &rest[..=end]
+        } else {
+            &rest[..end]
+        };
+
+        match window.rfind(' ').filter(|&i| i > 0) {
+            Some(space) => {
+                chunks.push(rest[..space].to_string());
+                rest = if keep_spaces {
+                    &rest[space..]
+                } else {
+                    &rest[space + 1..]
+                };
+            }
+            None => {
+                if end == 0 {
+                    end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+                }
+                chunks.push(rest[..end].to_string());
+                rest = &rest[end..];
+            }
+        }
+    }
+
+    if !rest.is_empty() {
+        chunks.push(rest.to_string());
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_line_is_untouched() {
+        assert_eq!(split_line("hello world", 20), vec!["hello world"]);
+    }
+
+    #[test]
+    fn breaks_at_last_space() {
+        assert_eq!(split_line("aaa bbb ccc ddd", 8), vec!["aaa bbb", "ccc ddd"]);
+    }
+
+    #[test]
+    fn breaks_at_space_on_the_limit() {
+        assert_eq!(split_line("aaaa bbbb", 4), vec!["aaaa", "bbbb"]);
+    }
+
+    #[test]
+    fn hard_breaks_long_words() {
+        assert_eq!(split_line("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn never_splits_code_points() {
+        let text = "ééééé";
+        let chunks = split_line(text, 3);
+        assert_eq!(chunks, vec!["é", "é", "é", "é", "é"]);
+        assert!(chunks.iter().all(|c| c.len() <= 3));
+    }
+
+    #[test]
+    fn wide_character_larger_than_limit() {
+        assert_eq!(split_line("🦀🦀", 2), vec!["🦀", "🦀"]);
+    }
+
+    #[test]
+    fn concat_chunks_join_back() {
+        let text = "aaa bbb ccc ddd eeeeeeeeee f";
+        let chunks = split_line_concat(text, 8);
+        assert_eq!(chunks, vec!["aaa bbb", " ccc ddd", " eeeeeee", "eee f"]);
+        assert!(chunks.iter().all(|c| c.len() <= 8));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn budget_accounts_for_prefix() {
+        let hostmask = "bridge!~bridge@example.com";
+        let budget = privmsg_budget(hostmask.len(), "#rust");
+        let line = format!(":{} PRIVMSG #rust :{}\r\n", hostmask, "x".repeat(budget));
+        assert_eq!(line.len(), MAX_LINE_BYTES);
+    }
+}
@@ -0,0 +1,450 @@
+//! Which Discord message is which IRC message, kept in SQLite so that edits, deletes, replies and reactions
+//! still find their counterpart after a restart.
+
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use poise::serenity_prelude::ChannelId;
+use poise::serenity_prelude::MessageId;
+use poise::serenity_prelude::UserId;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        -- a Discord user's message that was sent to IRC
+        discord_message INTEGER UNIQUE,
+        -- a webhook message that relayed an IRC message
+        webhook_message INTEGER UNIQUE,
+        irc_msgid TEXT,
+        channel INTEGER NOT NULL,
+        -- the Discord user ID, or the IRC nick
+        author TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        -- text of a Discord message as it was sent to IRC, to describe edits
+        content TEXT,
+        -- attachment, sticker and embed lines sent to IRC for a Discord message, one per line
+        attachments TEXT,
+        -- 1 if a Discord message went out as several IRC messages, so its msgid only covers the first
+        split INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS messages_irc_msgid ON messages (irc_msgid);
+    CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+";
+
+/// A relayed IRC message, as posted by a webhook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WebhookMessage {
+    pub channel: ChannelId,
+    pub message: MessageId,
+}
+
+/// A Discord message that was sent to IRC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    pub author: UserId,
+    /// `None` until its echo arrived, or if the server doesn't echo
+    pub msgid: Option<String>,
+    /// it went out as several IRC messages, or was edited there, so redacting `msgid` leaves some of it
+    pub split: bool,
+}
+
+/// `Connection` isn't `Sync`, and the store is shared across awaits.
+pub struct Store {
+    db: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(db: Connection) -> Result<Self> {
+        db.execute_batch(SCHEMA)?;
+        // older databases lack the columns added since
+        for (column, kind) in [
+            ("content", "TEXT"),
+            ("attachments", "TEXT"),
+            ("split", "INTEGER"),
+        ] {
+            let exists: bool = db.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('messages') WHERE name = ?1",
+                params![column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                db.execute(
+                    &format!("ALTER TABLE messages ADD COLUMN {} {}", column, kind),
+                    [],
+                )?;
+            }
+        }
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    /// Records a Discord message that was sent to IRC, whose msgid comes later with its echo.
+    pub fn sent_to_irc(
+        &self,
+        message: MessageId,
+        channel: ChannelId,
+        author: UserId,
+        content: &str,
+        attachments: &[String],
+    ) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO messages (discord_message, channel, author, timestamp, content, attachments) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![message.0, channel.0, author.0.to_string(), now(), content, attachments.join("\n")],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the text a Discord message was last sent to IRC with, or `None` if it never was.
+    pub fn sent_content(&self, message: MessageId) -> Result<Option<String>> {
+        let content: Option<Option<String>> = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT content FROM messages WHERE discord_message = ?1",
+                params![message.0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(content.map(Option::unwrap_or_default))
+    }
+
+    /// Updates the text of a Discord message after an edit was sent to IRC.
+    pub fn set_content(&self, message: MessageId, content: &str) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "UPDATE messages SET content = ?2 WHERE discord_message = ?1",
+            params![message.0, content],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the attachment, sticker and embed lines sent to IRC for a Discord message.
+    pub fn sent_attachments(&self, message: MessageId) -> Result<Vec<String>> {
+        let attachments: Option<Option<String>> = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT attachments FROM messages WHERE discord_message = ?1",
+                params![message.0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(attachments
+            .flatten()
+            .map(|a| a.lines().map(str::to_string).collect())
+            .unwrap_or_default())
+    }
+
+    /// Records lines sent to IRC for embeds added to a Discord message after it was sent.
+    pub fn add_attachments(&self, message: MessageId, lines: &[String]) -> Result<()> {
+        let mut attachments = self.sent_attachments(message)?;
+        attachments.extend_from_slice(lines);
+        self.db.lock().unwrap().execute(
+            "UPDATE messages SET attachments = ?2 WHERE discord_message = ?1",
+            params![message.0, attachments.join("\n")],
+        )?;
+        Ok(())
+    }
+
+    /// Sets the msgid of a Discord message sent to IRC, once its echo arrived.
+    pub fn set_msgid(&self, message: MessageId, msgid: &str) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "UPDATE messages SET irc_msgid = ?2 WHERE discord_message = ?1",
+            params![message.0, msgid],
+        )?;
+        Ok(())
+    }
+
+    /// Marks a Discord message as having gone out as several IRC messages, one msgid can't stand for all of them.
+    pub fn set_split(&self, message: MessageId) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "UPDATE messages SET split = 1 WHERE discord_message = ?1",
+            params![message.0],
+        )?;
+        Ok(())
+    }
+
+    /// Records the webhook message an IRC message was relayed as.
+    pub fn relayed_from_irc(
+        &self,
+        webhook: WebhookMessage,
+        msgid: Option<&str>,
+        nick: &str,
+    ) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO messages (webhook_message, irc_msgid, channel, author, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![webhook.message.0, msgid, webhook.channel.0, nick, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns who sent a Discord message to IRC and its msgid there, or `None` if it never was.
+    pub fn sent_message(&self, message: MessageId) -> Result<Option<SentMessage>> {
+        let sent = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT author, irc_msgid, split FROM messages WHERE discord_message = ?1",
+                params![message.0],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get(1)?,
+                        row.get::<_, Option<bool>>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        Ok(sent.and_then(|(author, msgid, split)| {
+            Some(SentMessage {
+                author: UserId(author.parse().ok()?),
+                msgid,
+                split: split.unwrap_or_default(),
+            })
+        }))
+    }
+
+    /// Returns the webhook message an IRC message was relayed as, if it was.
+    pub fn webhook_message(&self, msgid: &str) -> Result<Option<WebhookMessage>> {
+        let webhook = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT channel, webhook_message FROM messages WHERE irc_msgid = ?1 AND webhook_message IS NOT NULL",
+                params![msgid],
+                |row| {
+                    Ok(WebhookMessage {
+                        channel: ChannelId(row.get(0)?),
+                        message: MessageId(row.get(1)?),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(webhook)
+    }
+
+    /// Forgets a deleted message, whichever side it came from.
+    pub fn forget(&self, message: MessageId) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "DELETE FROM messages WHERE discord_message = ?1 OR webhook_message = ?1",
+            params![message.0],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the IRC msgid of a Discord message, whichever side it came from.
+    pub fn msgid(&self, message: MessageId) -> Result<Option<String>> {
+        let msgid = self
+            .db
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT irc_msgid FROM messages WHERE discord_message = ?1 OR webhook_message = ?1",
+                params![message.0],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(msgid.flatten())
+    }
+
+    /// Forgets messages older than `retention`, returning how many.
+    pub fn prune(&self, retention: Duration) -> Result<usize> {
+        let cutoff = now().saturating_sub(retention.as_secs() as i64);
+        let pruned = self
+            .db
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM messages WHERE timestamp < ?1", params![cutoff])?;
+        Ok(pruned)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discord_message_gets_msgid_from_echo() {
+        let store = Store::in_memory().unwrap();
+        store
+            .sent_to_irc(MessageId(1), ChannelId(10), UserId(100), "hi", &[])
+            .unwrap();
+        assert_eq!(store.msgid(MessageId(1)).unwrap(), None);
+
+        store.set_msgid(MessageId(1), "abc").unwrap();
+        assert_eq!(store.msgid(MessageId(1)).unwrap().as_deref(), Some("abc"));
+        assert_eq!(store.msgid(MessageId(2)).unwrap(), None);
+    }
+
+    #[test]
+    fn remembers_content_for_edits() {
+        let store = Store::in_memory().unwrap();
+        store
+            .sent_to_irc(MessageId(1), ChannelId(10), UserId(100), "teh cake", &[])
+            .unwrap();
+        assert_eq!(
+            store.sent_content(MessageId(1)).unwrap().as_deref(),
+            Some("teh cake")
+        );
+
+        store.set_content(MessageId(1), "the cake").unwrap();
+        assert_eq!(
+            store.sent_content(MessageId(1)).unwrap().as_deref(),
+            Some("the cake")
+        );
+        assert_eq!(store.sent_content(MessageId(2)).unwrap(), None);
+    }
+
+    #[test]
+    fn remembers_attachments_for_embed_updates() {
+        let store = Store::in_memory().unwrap();
+        let files = vec!["[file: a.png (1 KiB)] https://example.com/a.png".to_string()];
+        store
+            .sent_to_irc(MessageId(1), ChannelId(10), UserId(100), "look", &files)
+            .unwrap();
+        assert_eq!(store.sent_attachments(MessageId(1)).unwrap(), files);
+
+        let embeds = vec!["[embed] Example - https://example.com".to_string()];
+        store.add_attachments(MessageId(1), &embeds).unwrap();
+        assert_eq!(
+            store.sent_attachments(MessageId(1)).unwrap(),
+            [files, embeds].concat()
+        );
+        assert!(store.sent_attachments(MessageId(2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn adds_content_to_old_databases() {
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE messages (discord_message INTEGER UNIQUE, webhook_message INTEGER UNIQUE, irc_msgid TEXT, channel INTEGER NOT NULL, author TEXT NOT NULL, timestamp INTEGER NOT NULL);",
+        )
+        .unwrap();
+        let store = Store::init(db).unwrap();
+
+        store
+            .sent_to_irc(MessageId(1), ChannelId(10), UserId(100), "hi", &[])
+            .unwrap();
+        assert_eq!(
+            store.sent_content(MessageId(1)).unwrap().as_deref(),
+            Some("hi")
+        );
+        assert!(store.sent_attachments(MessageId(1)).unwrap().is_empty());
+        store.set_split(MessageId(1)).unwrap();
+        assert!(store.sent_message(MessageId(1)).unwrap().unwrap().split);
+    }
+
+    #[test]
+    fn remembers_messages_sent_in_pieces() {
+        let store = Store::in_memory().unwrap();
+        for id in [1, 2] {
+            store
+                .sent_to_irc(MessageId(id), ChannelId(10), UserId(100), "hi", &[])
+                .unwrap();
+        }
+        store.set_split(MessageId(2)).unwrap();
+
+        assert!(!store.sent_message(MessageId(1)).unwrap().unwrap().split);
+        assert!(store.sent_message(MessageId(2)).unwrap().unwrap().split);
+    }
+
+    #[test]
+    fn webhook_message_keeps_msgid() {
+        let store = Store::in_memory().unwrap();
+        let webhook = WebhookMessage {
+            channel: ChannelId(10),
+            message: MessageId(5),
+        };
+        store
+            .relayed_from_irc(webhook, Some("xyz"), "alice")
+            .unwrap();
+
+        assert_eq!(store.msgid(MessageId(5)).unwrap().as_deref(), Some("xyz"));
+        assert_eq!(store.webhook_message("xyz").unwrap(), Some(webhook));
+    }
+
+    #[test]
+    fn redactions_only_find_webhook_messages() {
+        let store = Store::in_memory().unwrap();
+        store
+            .sent_to_irc(MessageId(1), ChannelId(10), UserId(100), "hi", &[])
+            .unwrap();
+        store.set_msgid(MessageId(1), "abc").unwrap();
+
+        assert_eq!(store.webhook_message("abc").unwrap(), None);
+        assert_eq!(store.webhook_message("nope").unwrap(), None);
+    }
+
+    #[test]
+    fn deleted_messages_are_forgotten() {
+        let store = Store::in_memory().unwrap();
+        store
+            .sent_to_irc(MessageId(1), ChannelId(10), UserId(100), "hi", &[])
+            .unwrap();
+        assert_eq!(
+            store.sent_message(MessageId(1)).unwrap(),
+            Some(SentMessage {
+                author: UserId(100),
+                msgid: None,
+                split: false
+            })
+        );
+        store.set_msgid(MessageId(1), "abc").unwrap();
+        assert_eq!(
+            store
+                .sent_message(MessageId(1))
+                .unwrap()
+                .unwrap()
+                .msgid
+                .as_deref(),
+            Some("abc")
+        );
+
+        store.forget(MessageId(1)).unwrap();
+        assert_eq!(store.sent_message(MessageId(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn prunes_old_messages() {
+        let store = Store::in_memory().unwrap();
+        store
+            .sent_to_irc(MessageId(1), ChannelId(10), UserId(100), "hi", &[])
+            .unwrap();
+        store.set_msgid(MessageId(1), "abc").unwrap();
+        store
+            .db
+            .lock()
+            .unwrap()
+            .execute("UPDATE messages SET timestamp = timestamp - 7200", [])
+            .unwrap();
+        store
+            .sent_to_irc(MessageId(2), ChannelId(10), UserId(100), "hi", &[])
+            .unwrap();
+
+        assert_eq!(store.prune(Duration::from_secs(3600)).unwrap(), 1);
+        assert_eq!(store.msgid(MessageId(1)).unwrap(), None);
+        assert_eq!(store.prune(Duration::from_secs(3600)).unwrap(), 0);
+    }
+}